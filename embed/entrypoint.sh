@@ -6,6 +6,7 @@ trap 'exit 143' SIGTERM
 set -o errexit -o pipefail -o nounset
 
 is_bootc_container=$1
+run_command=( "${@:2}" )  # command to run in one-shot mode, if any
 
 # clean up locks that may have been left around from the container being killed
 rm -fr /var/lock
@@ -86,9 +87,14 @@ function __bg_ensure_tty() {
 virsh=( virsh --connect "qemu+unix:///session?socket=$socket" --quiet )
 
 if [[ -z "$( "${virsh[@]}" list --all --name )" ]]; then
+    /crun-vm/crun-vm in-container set-up-network-interfaces /crun-vm/domain.xml
     "${virsh[@]}" define /crun-vm/domain.xml
 fi
 
+# fail early if the ports to forward are taken, e.g., by other containers in the
+# same pod
+/crun-vm/crun-vm in-container check-forwarded-ports /crun-vm/domain.xml
+
 # trigger graceful shutdown and wait for VM to terminate
 function __shutdown() {
     (
@@ -103,10 +109,97 @@ function __shutdown() {
     )
 }
 
+# with --crash-dump, when the VM crashes, save its memory, domain XML, and console
+# log, and then power it off so that the container exits with status 134
+rm -f /crun-vm/crashed
+
+function __save_crash_dump() {
+    (
+        set -o errexit -o pipefail -o nounset
+        while sleep 1; do
+            state=$( "${virsh[@]}" domstate domain --reason 2>/dev/null ) || continue
+            [[ "$state" == crashed* || "$state" == 'paused (watchdog)' ]] && break
+        done
+        dir=/crun-vm/crash-dumps/$( date --utc +%Y%m%dT%H%M%SZ )
+        mkdir "$dir"
+        "${virsh[@]}" dumpxml domain >"$dir/domain.xml"
+        "${virsh[@]}" dump domain "$dir/vmcore" --memory-only \
+            --format "$( cat /crun-vm/crash-dump-format )"
+        cp /crun-vm/console.log "$dir/console.log"
+        touch /crun-vm/crashed
+        "${virsh[@]}" destroy domain
+    )
+}
+
+if [[ -e /crun-vm/crash-dump-format ]]; then
+    __save_crash_dump &
+    disown
+fi
+
+# with --gdb-wait, only run the VM once a debugger attaches and continues it
+start_opts=()
+if [[ -e /crun-vm/start-paused ]]; then
+    start_opts=( --paused )
+fi
+
+# let exec know the vsock CID that libvirt assigns to the VM once it is started,
+# if it has a vsock device (disowned so that the final wait ignores it)
+if grep --quiet '<vsock ' /crun-vm/domain.xml; then
+    (
+        set -o errexit -o pipefail -o nounset
+        until cid=$(
+            "${virsh[@]}" dumpxml domain 2>/dev/null |
+                sed -n "s/.*<cid auto='yes' address='\([0-9]*\)'.*/\1/p"
+        ) && [[ -n "$cid" ]]; do
+            sleep 0.2
+        done
+        echo "$cid" >/crun-vm/vsock-cid.tmp
+        mv /crun-vm/vsock-cid.tmp /crun-vm/vsock-cid
+    ) &
+    disown
+fi
+
+# keep /crun-vm/guest-addresses up to date with the VM's IP addresses, as
+# reported by its guest agent (disowned so that the final wait ignores it)
+/crun-vm/crun-vm in-container report-guest-addresses /crun-vm/guest-addresses &
+disown
+
+# with Podman's --sdnotify=container, only tell systemd that we're ready once the
+# VM has finished booting (disowned so that the final wait ignores it)
+if [[ -n "${NOTIFY_SOCKET:-}" ]]; then
+    /crun-vm/crun-vm in-container notify-ready /crun-vm/ready.sock &
+    disown
+fi
+
+if (( ${#run_command[@]} > 0 )); then
+
+    # One-shot mode: boot the VM without attaching to its console, so that the
+    # container's output is only the command's output. Run the command the same
+    # way podman-exec would, which waits until the VM can be reached. Finally,
+    # power the VM off and exit with the command's status.
+    "${virsh[@]}" start domain "${start_opts[@]}"
+    trap '__shutdown || true; exit 143' SIGTERM
+
+    # As below, run the command in the background so that the SIGTERM trap runs
+    # right away, while still giving it our stdin.
+    "${run_command[@]}" <&0 &
+
+    set +o errexit
+    wait "$!"
+    exit_code=$?
+    set -o errexit
+
+    __shutdown || true
+    [[ ! -e /crun-vm/crashed ]] || exit 134
+    exit "$exit_code"
+
+fi
+
 # We're running as PID 1, so if we run virsh in the foreground, SIGTERM will not
 # be propagated to it. We thus run it in the background but keep our tty
 # attached to its stdin. We then set up a trap that attempts to gracefully
 # terminate the VM on SIGTERM, and finally block waiting for virsh to exit.
-__bg_ensure_tty "${virsh[@]}" start domain --console
+__bg_ensure_tty "${virsh[@]}" start domain --console "${start_opts[@]}"
 trap '__shutdown || true; exit 143' SIGTERM
 wait
+[[ ! -e /crun-vm/crashed ]] || exit 134
@@ -0,0 +1,18 @@
+#!/bin/bash
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+# libvirt runs QEMU with umask 002, so QEMU would create the sockets requested
+# using --qmp-socket and --gdb with group and other permissions. When those
+# options are given, we thus tell libvirt to run QEMU through this script
+# instead, which ensures they are only ever accessible to the container's user.
+
+umask 0077
+
+for qemu in "/usr/bin/qemu-system-$( uname -m )" /usr/libexec/qemu-kvm; do
+    if [[ -x "$qemu" ]]; then
+        exec "$qemu" "$@"
+    fi
+done
+
+echo "QEMU not found" >&2
+exit 1
@@ -3,7 +3,7 @@
 use std::iter;
 use std::str::FromStr;
 
-use anyhow::{anyhow, ensure, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use lazy_static::lazy_static;
@@ -41,6 +41,288 @@ impl FromStr for Blockdev {
     }
 }
 
+/// A named channel between the guest and a unix socket on the host.
+#[derive(Clone, Debug)]
+pub struct Channel {
+    pub name: String,
+    pub path: Utf8PathBuf,
+}
+
+impl FromStr for Channel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Channel> {
+        lazy_static! {
+            static ref PATTERN: Regex = Regex::new(r"^([a-zA-Z0-9_.-]+)=(.+)$").unwrap();
+        }
+
+        let captures = PATTERN
+            .captures(s)
+            .ok_or_else(|| anyhow!("invalid --channel option, expected <name>=<socket path>"))?;
+
+        let channel = Channel {
+            name: captures[1].to_string(),
+            path: parse_socket_path(&captures[2])?,
+        };
+
+        Ok(channel)
+    }
+}
+
+/// A mapping between guest and container user or group IDs for virtiofs shares.
+#[derive(Clone, Debug)]
+pub struct VirtiofsIdMap {
+    /// If set, the path in the guest of the only share the mapping applies to.
+    pub target: Option<Utf8PathBuf>,
+    pub guest_id: u32,
+    pub container_id: u32,
+    pub count: u32,
+}
+
+impl FromStr for VirtiofsIdMap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<VirtiofsIdMap> {
+        lazy_static! {
+            static ref PATTERN: Regex = Regex::new(r"^(?:([^=]+)=)?(\d+):(\d+):(\d+)$").unwrap();
+        }
+
+        let captures = PATTERN
+            .captures(s)
+            .ok_or_else(|| anyhow!("invalid virtiofs ID mapping '{s}'"))?;
+
+        let map = VirtiofsIdMap {
+            target: captures.get(1).map(|m| Utf8PathBuf::from(m.as_str())),
+            guest_id: captures[2].parse()?,
+            container_id: captures[3].parse()?,
+            count: captures[4].parse()?,
+        };
+
+        ensure!(map.count > 0, "virtiofs ID mapping '{s}' has a count of 0");
+        ensure!(
+            map.target.as_ref().map_or(true, |t| t.is_absolute()),
+            "virtiofs ID mapping '{s}' must have an absolute path"
+        );
+
+        Ok(map)
+    }
+}
+
+/// Where QEMU's gdbstub listens for debugger connections.
+#[derive(Clone, Debug)]
+pub enum GdbStub {
+    /// A TCP port in the container's network namespace.
+    Port(u16),
+    Socket(Utf8PathBuf),
+}
+
+impl FromStr for GdbStub {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<GdbStub> {
+        if s.bytes().all(|b| b.is_ascii_digit()) {
+            let port: u16 = s.parse().map_err(|_| anyhow!("invalid --gdb port '{s}'"))?;
+            ensure!(port > 0, "invalid --gdb port '{s}'");
+            Ok(GdbStub::Port(port))
+        } else {
+            Ok(GdbStub::Socket(parse_socket_path(s)?))
+        }
+    }
+}
+
+/// Ports to forward from the container's network namespace to the guest.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PortForward {
+    /// Forward no ports at all.
+    None,
+    Range {
+        protocol: String,
+        start: u16,
+        end: u16,
+        /// The guest port that `start` is forwarded to.
+        guest_start: u16,
+    },
+}
+
+impl FromStr for PortForward {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<PortForward> {
+        lazy_static! {
+            static ref PATTERN: Regex =
+                Regex::new(r"^(\d+)(?:-(\d+))?(?::(\d+))?(?:/(tcp|udp))?$").unwrap();
+        }
+
+        if s == "none" {
+            return Ok(PortForward::None);
+        }
+
+        let captures = PATTERN
+            .captures(s)
+            .ok_or_else(|| anyhow!("invalid --forward option '{s}'"))?;
+
+        let start: u16 = captures[1].parse()?;
+        let end: u16 = captures.get(2).map_or(Ok(start), |m| m.as_str().parse())?;
+        let guest_start: u16 = captures.get(3).map_or(Ok(start), |m| m.as_str().parse())?;
+        let protocol = captures.get(4).map_or("tcp", |m| m.as_str()).to_string();
+
+        ensure!(
+            start > 0 && guest_start > 0,
+            "invalid port 0 in --forward '{s}'"
+        );
+        ensure!(end >= start, "invalid port range in --forward '{s}'");
+        ensure!(
+            u32::from(guest_start) + u32::from(end - start) <= u32::from(u16::MAX),
+            "guest port range out of bounds in --forward '{s}'"
+        );
+
+        Ok(PortForward::Range {
+            protocol,
+            start,
+            end,
+            guest_start,
+        })
+    }
+}
+
+/// How the guest is connected to the container's network.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum NetworkMode {
+    /// No network interfaces.
+    None,
+    /// A single network interface backed by passt, if the container has any network interfaces.
+    #[default]
+    Passt,
+    /// A network interface backed by passt for each of the container's network interfaces.
+    PasstAll,
+}
+
+/// The format of guest memory dumps taken when the guest crashes, as understood by `virsh dump`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum CrashDumpFormat {
+    #[default]
+    Elf,
+    KdumpZlib,
+    KdumpLzo,
+    KdumpSnappy,
+}
+
+/// How directories are shared with the guest.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum ShareBackend {
+    #[default]
+    Virtiofs,
+    #[clap(name = "9p")]
+    NineP,
+}
+
+/// Tuning options for virtiofs shares.
+#[derive(Clone, Debug, Default)]
+pub struct VirtiofsOptions {
+    /// If set, the path in the guest of the only share the options apply to.
+    pub target: Option<Utf8PathBuf>,
+    pub cache: Option<String>,
+    pub xattr: Option<bool>,
+    pub posix_acl: Option<bool>,
+    pub thread_pool_size: Option<u32>,
+    pub queue_size: Option<u32>,
+}
+
+impl VirtiofsOptions {
+    /// Returns these options with the ones set in `other` taking precedence.
+    pub fn merged_with(&self, other: &VirtiofsOptions) -> VirtiofsOptions {
+        VirtiofsOptions {
+            target: other.target.clone().or_else(|| self.target.clone()),
+            cache: other.cache.clone().or_else(|| self.cache.clone()),
+            xattr: other.xattr.or(self.xattr),
+            posix_acl: other.posix_acl.or(self.posix_acl),
+            thread_pool_size: other.thread_pool_size.or(self.thread_pool_size),
+            queue_size: other.queue_size.or(self.queue_size),
+        }
+    }
+}
+
+impl FromStr for VirtiofsOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<VirtiofsOptions> {
+        fn parse_switch(key: &str, value: &str) -> Result<bool> {
+            match value {
+                "on" => Ok(true),
+                "off" => Ok(false),
+                _ => bail!("--virtiofs {key} must be 'on' or 'off'"),
+            }
+        }
+
+        let mut options = VirtiofsOptions::default();
+
+        for pair in s.split(',') {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid --virtiofs option '{pair}'"))?;
+
+            match key {
+                "target" => {
+                    let target = Utf8PathBuf::from(value);
+                    ensure!(target.is_absolute(), "--virtiofs target must be absolute");
+                    options.target = Some(target);
+                }
+                "cache" => {
+                    ensure!(
+                        ["auto", "always", "never", "metadata"].contains(&value),
+                        "--virtiofs cache must be one of 'auto', 'always', 'never', or 'metadata'"
+                    );
+                    options.cache = Some(value.to_string());
+                }
+                "xattr" => options.xattr = Some(parse_switch(key, value)?),
+                "posix-acl" => options.posix_acl = Some(parse_switch(key, value)?),
+                "thread-pool-size" => options.thread_pool_size = Some(value.parse()?),
+                "queue-size" => {
+                    let size: u32 = value.parse()?;
+                    ensure!(
+                        size.is_power_of_two() && size <= 1024,
+                        "--virtiofs queue-size must be a power of 2 no greater than 1024"
+                    );
+                    options.queue_size = Some(size);
+                }
+                "dax-window" => bail!(
+                    "--virtiofs dax-window is not supported, as neither QEMU nor virtiofsd support DAX"
+                ),
+                _ => bail!("unknown --virtiofs option '{key}'"),
+            }
+        }
+
+        ensure!(
+            options.posix_acl != Some(true) || options.xattr != Some(false),
+            "--virtiofs posix-acl=on requires xattr=on"
+        );
+
+        Ok(options)
+    }
+}
+
+fn parse_socket_path(s: &str) -> Result<Utf8PathBuf> {
+    let path = Utf8PathBuf::from(s);
+    ensure!(path.file_name().is_some(), "invalid socket path {path}");
+    Ok(path)
+}
+
+fn parse_mac_address(s: &str) -> Result<String> {
+    lazy_static! {
+        static ref PATTERN: Regex = Regex::new(r"^[0-9a-f]{2}(:[0-9a-f]{2}){5}$").unwrap();
+    }
+
+    let mac = s.to_lowercase();
+
+    ensure!(PATTERN.is_match(&mac), "invalid MAC address '{s}'");
+    ensure!(
+        u8::from_str_radix(&mac[..2], 16)? & 1 == 0,
+        "MAC address '{s}' is a multicast address"
+    );
+
+    Ok(mac)
+}
+
 #[derive(clap::Parser, Debug)]
 pub struct CustomOptions {
     #[clap(long)]
@@ -49,6 +331,45 @@ pub struct CustomOptions {
     #[clap(long)]
     pub persistent: bool,
 
+    #[clap(long, value_enum, default_value_t)]
+    pub network: NetworkMode,
+
+    #[clap(long, value_parser = parse_mac_address)]
+    pub mac_address: Option<String>,
+
+    #[clap(long)]
+    pub forward: Vec<PortForward>,
+
+    #[clap(long)]
+    pub channel: Vec<Channel>,
+
+    #[clap(long, value_parser = parse_socket_path)]
+    pub qmp_socket: Option<Utf8PathBuf>,
+
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "1234")]
+    pub gdb: Option<GdbStub>,
+
+    #[clap(long, requires = "gdb")]
+    pub gdb_wait: bool,
+
+    #[clap(long)]
+    pub crash_dump: Option<Utf8PathBuf>,
+
+    #[clap(long, value_enum, default_value_t, requires = "crash_dump")]
+    pub crash_dump_format: CrashDumpFormat,
+
+    #[clap(long, value_enum)]
+    pub share_backend: Option<ShareBackend>,
+
+    #[clap(long)]
+    pub virtiofs: Vec<VirtiofsOptions>,
+
+    #[clap(long)]
+    pub virtiofs_uid_map: Vec<VirtiofsIdMap>,
+
+    #[clap(long)]
+    pub virtiofs_gid_map: Vec<VirtiofsIdMap>,
+
     #[clap(long)]
     pub random_ssh_key_pair: bool,
 
@@ -68,12 +389,21 @@ pub struct CustomOptions {
     pub password: Option<String>,
 
     #[clap(long)]
-    pub merge_libvirt_xml: Vec<Utf8PathBuf>,
+    pub run: Option<String>,
+
+    #[clap(long, requires = "run")]
+    pub run_as: Option<String>,
+
+    #[clap(long, requires = "run")]
+    pub run_timeout: Option<u32>,
 
     #[clap(long)]
+    pub merge_libvirt_xml: Vec<Utf8PathBuf>,
+
+    #[clap(long, conflicts_with = "run")]
     pub print_libvirt_xml: bool,
 
-    #[clap(long, conflicts_with = "print_libvirt_xml")]
+    #[clap(long, conflicts_with_all = ["print_libvirt_xml", "run"])]
     pub print_config_json: bool,
 }
 
@@ -102,6 +432,12 @@ impl CustomOptions {
             }
         }
 
+        if args.first().is_some_and(|&a| a == "--") {
+            // Podman passes along a -- given after the image, as in `podman run <image> -- --run
+            // <cmd>`, so gobble it up to let users delimit options like that.
+            args.remove(0);
+        }
+
         if let Some(&first_arg) = args.first() {
             ensure!(
                 first_arg.starts_with('-'),
@@ -113,6 +449,47 @@ impl CustomOptions {
             iter::once(&"podman run [<podman-opts>] <image>".to_string()).chain(args),
         );
 
+        ensure!(
+            options.network != NetworkMode::None || options.forward.is_empty(),
+            "--forward can't be used with --network none"
+        );
+
+        ensure!(
+            !options.forward.contains(&PortForward::None) || options.forward.len() == 1,
+            "--forward none can't be combined with other --forward options"
+        );
+
+        if options.forward.is_empty() && engine == Engine::Kubernetes {
+            // Forwarding all ports would keep other containers in the pod from using any, so only
+            // forward the ports that the pod spec declares for this container, if any.
+            options.forward = get_kubernetes_container_ports(spec)?;
+        }
+
+        for (i, channel) in options.channel.iter().enumerate() {
+            ensure!(
+                options.channel[..i].iter().all(|c| c.name != channel.name),
+                "--channel name '{}' given more than once",
+                channel.name
+            );
+        }
+
+        if let Some(GdbStub::Port(port)) = options.gdb {
+            let is_forwarded = |f: &PortForward| match f {
+                PortForward::None => false,
+                PortForward::Range {
+                    protocol,
+                    start,
+                    end,
+                    ..
+                } => protocol == "tcp" && (*start..=*end).contains(&port),
+            };
+
+            ensure!(
+                !options.forward.iter().any(is_forwarded),
+                "--gdb port {port} can't also be forwarded to the VM"
+            );
+        }
+
         ensure!(
             !spec.root().as_ref().unwrap().readonly().unwrap_or(false) || !options.persistent,
             "--persistent was set but the container's root file system was mounted as read-only"
@@ -131,8 +508,7 @@ impl CustomOptions {
                 .iter()
                 .flatten()
                 .filter(|m| m.source().is_some())
-                .filter(|m| path.starts_with(m.destination()))
-                .last()
+                .rfind(|m| path.starts_with(m.destination()))
                 .ok_or_else(|| anyhow!("can't find {}", path))?;
 
             let mount_source: &Utf8Path = mount.source().as_deref().unwrap().try_into()?;
@@ -152,12 +528,19 @@ impl CustomOptions {
         // absolute.
         ensure!(
             all_are_absolute(options.blockdev.iter().flat_map(|b| [&b.source, &b.target]))
+                && all_are_absolute(options.channel.iter().map(|c| &c.path))
+                && all_are_absolute(&options.qmp_socket)
+                && all_are_absolute(options.gdb.iter().filter_map(|g| match g {
+                    GdbStub::Port(_) => None,
+                    GdbStub::Socket(path) => Some(path),
+                }))
+                && all_are_absolute(&options.crash_dump)
                 && all_are_absolute(&options.cloud_init)
                 && all_are_absolute(&options.ignition)
                 && all_are_absolute(&options.merge_libvirt_xml),
             concat!(
-                "paths specified using --blockdev, --cloud-init, --ignition, or",
-                " --merge-libvirt-xml must be absolute",
+                "paths specified using --blockdev, --channel, --qmp-socket, --gdb, --crash-dump,",
+                " --cloud-init, --ignition, or --merge-libvirt-xml must be absolute",
             ),
         );
 
@@ -167,6 +550,27 @@ impl CustomOptions {
                 blockdev.target = path_in_container_into_path_in_host(spec, &blockdev.target)?;
             }
 
+            for channel in &mut options.channel {
+                // the socket itself is only created once the VM starts
+                let dir =
+                    path_in_container_into_path_in_host(spec, channel.path.parent().unwrap())?;
+                channel.path = dir.join(channel.path.file_name().unwrap());
+            }
+
+            if let Some(path) = &mut options.qmp_socket {
+                let dir = path_in_container_into_path_in_host(spec, path.parent().unwrap())?;
+                *path = dir.join(path.file_name().unwrap());
+            }
+
+            if let Some(GdbStub::Socket(path)) = &mut options.gdb {
+                let dir = path_in_container_into_path_in_host(spec, path.parent().unwrap())?;
+                *path = dir.join(path.file_name().unwrap());
+            }
+
+            if let Some(path) = &mut options.crash_dump {
+                *path = path_in_container_into_path_in_host(spec, path)?;
+            }
+
             if let Some(path) = &mut options.cloud_init {
                 *path = path_in_container_into_path_in_host(spec, path)?;
             }
@@ -183,3 +587,46 @@ impl CustomOptions {
         Ok(options)
     }
 }
+
+/// Returns the ports declared in the pod spec for the container, which kubelet passes along in an
+/// annotation.
+fn get_kubernetes_container_ports(spec: &oci_spec::runtime::Spec) -> Result<Vec<PortForward>> {
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct ContainerPort {
+        container_port: u16,
+        #[serde(default)]
+        protocol: Option<String>,
+    }
+
+    let Some(annotation) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get("io.kubernetes.container.ports"))
+    else {
+        return Ok(vec![]);
+    };
+
+    let ports: Vec<ContainerPort> = serde_json::from_str(annotation)
+        .map_err(|e| anyhow!("invalid io.kubernetes.container.ports annotation: {e}"))?;
+
+    let forwards = ports
+        .into_iter()
+        .filter_map(|p| {
+            let protocol = match p.protocol.as_deref().unwrap_or("TCP") {
+                "TCP" => "tcp",
+                "UDP" => "udp",
+                _ => return None, // e.g., SCTP, which passt doesn't support
+            };
+
+            Some(PortForward::Range {
+                protocol: protocol.to_string(),
+                start: p.container_port,
+                end: p.container_port,
+                guest_start: p.container_port,
+            })
+        })
+        .collect();
+
+    Ok(forwards)
+}
@@ -8,8 +8,11 @@ use anyhow::{ensure, Result};
 use camino::Utf8Path;
 use xml::writer::XmlEvent;
 
-use crate::commands::create::custom_opts::CustomOptions;
-use crate::commands::create::Mounts;
+use crate::commands::create::custom_opts::{
+    CustomOptions, GdbStub, NetworkMode, PortForward, ShareBackend,
+};
+use crate::commands::create::{get_guest_env, is_ready_notification_requested, Mounts};
+use crate::commands::in_container::network::{ALL_INTERFACES_ALIAS, DEFAULT_INTERFACE_ALIAS};
 use crate::util::{SpecExt, VmImageInfo};
 
 pub fn set_up_libvirt_domain_xml(
@@ -17,10 +20,20 @@ pub fn set_up_libvirt_domain_xml(
     vm_image_info: &VmImageInfo,
     mounts: &Mounts,
     custom_options: &CustomOptions,
+    vsock: bool,
+    mac_address: &str,
 ) -> Result<()> {
     let path = spec.root_path()?.join("crun-vm/domain.xml");
 
-    generate(&path, spec, vm_image_info, mounts, custom_options)?;
+    generate(
+        &path,
+        spec,
+        vm_image_info,
+        mounts,
+        custom_options,
+        vsock,
+        mac_address,
+    )?;
     merge_overlays(&path, &custom_options.merge_libvirt_xml)?;
 
     Ok(())
@@ -32,6 +45,8 @@ fn generate(
     vm_image_info: &VmImageInfo,
     mounts: &Mounts,
     custom_options: &CustomOptions,
+    vsock: bool,
+    mac_address: &str,
 ) -> Result<()> {
     let mut w = xml::EmitterConfig::new()
         .perform_indent(true)
@@ -42,150 +57,369 @@ fn generate(
         false => "kvm",
     };
 
-    s(&mut w, "domain", &[("type", domain_type)], |w| {
-        st(w, "name", &[], "domain")?;
+    let qemu_args = get_qemu_args(spec, custom_options);
 
-        se(w, "cpu", &[("mode", "maximum")])?;
+    let namespaces: &[(&str, &str)] = match qemu_args.is_empty() {
+        true => &[],
+        false => &[("qemu", QEMU_NAMESPACE)],
+    };
 
-        let vcpus = get_vcpu_count(spec).to_string();
-        if let Some(cpu_set) = get_cpu_set(spec) {
-            st(w, "vcpu", &[("cpuset", cpu_set.as_str())], vcpus.as_str())?;
-        } else {
-            st(w, "vcpu", &[], vcpus.as_str())?;
-        }
+    s_ns(
+        &mut w,
+        "domain",
+        &[("type", domain_type)],
+        namespaces,
+        |w| {
+            st(w, "name", &[], "domain")?;
+
+            se(w, "cpu", &[("mode", "maximum")])?;
+
+            let vcpus = get_vcpu_count(spec).to_string();
+            if let Some(cpu_set) = get_cpu_set(spec) {
+                st(w, "vcpu", &[("cpuset", cpu_set.as_str())], vcpus.as_str())?;
+            } else {
+                st(w, "vcpu", &[], vcpus.as_str())?;
+            }
 
-        let memory = get_memory_size(spec).to_string();
-        st(w, "memory", &[("unit", "b")], memory.as_str())?;
+            let memory = get_memory_size(spec).to_string();
+            st(w, "memory", &[("unit", "b")], memory.as_str())?;
 
-        s(w, "os", &[("firmware", "efi")], |w| {
-            let attrs = match ["x86", "x86_64"].contains(&env::consts::ARCH) {
-                true => [("machine", "q35")].as_slice(),
-                false => [].as_slice(), // use libvirt's default
-            };
-            st(w, "type", attrs, "hvm")?;
+            s(w, "os", &[("firmware", "efi")], |w| {
+                let attrs = match ["x86", "x86_64"].contains(&env::consts::ARCH) {
+                    true => [("machine", "q35")].as_slice(),
+                    false => [].as_slice(), // use libvirt's default
+                };
+                st(w, "type", attrs, "hvm")?;
 
-            s(w, "firmware", &[], |w| {
-                se(w, "feature", &[("enabled", "no"), ("name", "secure-boot")])
-            })?;
+                s(w, "firmware", &[], |w| {
+                    se(w, "feature", &[("enabled", "no"), ("name", "secure-boot")])
+                })?;
 
-            Ok(())
-        })?;
-
-        // fw_cfg requires ACPI
-        s(w, "features", &[], |w| se(w, "acpi", &[]))?;
-
-        s(w, "sysinfo", &[("type", "fwcfg")], |w| {
-            se(
-                w,
-                "entry",
-                &[
-                    ("name", "opt/com.coreos/config"),
-                    ("file", "/crun-vm/first-boot/ignition.ign"),
-                ],
-            )
-        })?;
-
-        if !mounts.virtiofs.is_empty() {
-            s(w, "memoryBacking", &[], |w| {
-                se(w, "source", &[("type", "memfd")])?;
-                se(w, "access", &[("mode", "shared")])?;
                 Ok(())
             })?;
-        }
 
-        s(w, "devices", &[], |w| {
-            s(w, "serial", &[("type", "pty")], |w| {
-                se(w, "target", &[("port", "0")])
-            })?;
-            s(w, "console", &[("type", "pty")], |w| {
-                se(w, "target", &[("type", "serial"), ("port", "0")])
-            })?;
+            if custom_options.crash_dump.is_some() {
+                // keep the guest around so the entrypoint can dump its memory
+                st(w, "on_crash", &[], "preserve")?;
+            }
 
-            let mut next_dev_index = 0;
-            let mut next_dev_name = || {
-                let i = next_dev_index;
-                next_dev_index += 1;
-                format!("vd{}", ('a'..='z').cycle().nth(i).unwrap())
-            };
+            // fw_cfg requires ACPI
+            s(w, "features", &[], |w| se(w, "acpi", &[]))?;
 
-            s(w, "disk", &[("type", "file"), ("device", "disk")], |w| {
-                se(w, "target", &[("dev", &next_dev_name()), ("bus", "virtio")])?;
+            s(w, "sysinfo", &[("type", "fwcfg")], |w| {
                 se(
                     w,
-                    "driver",
-                    &[("name", "qemu"), ("type", &vm_image_info.format)],
-                )?;
-                se(w, "source", &[("file", vm_image_info.path.as_str())])?;
-                Ok(())
+                    "entry",
+                    &[
+                        ("name", "opt/com.coreos/config"),
+                        ("file", "/crun-vm/first-boot/ignition.ign"),
+                    ],
+                )
             })?;
 
-            for (i, dev) in mounts.block_device.iter().enumerate() {
-                let typ = if dev.is_regular_file { "file" } else { "block" };
-                let source_attr = if dev.is_regular_file { "file" } else { "dev" };
+            if !mounts.virtiofs.is_empty() && mounts.share_backend == ShareBackend::Virtiofs {
+                s(w, "memoryBacking", &[], |w| {
+                    se(w, "source", &[("type", "memfd")])?;
+                    se(w, "access", &[("mode", "shared")])?;
+                    Ok(())
+                })?;
+            }
+
+            s(w, "devices", &[], |w| {
+                // restrict the sockets requested using --qmp-socket and --gdb to the container's
+                // user from the start, see qemu.sh
+                let has_private_sockets = custom_options.qmp_socket.is_some()
+                    || matches!(custom_options.gdb, Some(GdbStub::Socket(_)));
+                if has_private_sockets {
+                    st(w, "emulator", &[], "/crun-vm/qemu.sh")?;
+                }
+
+                s(w, "serial", &[("type", "pty")], |w| {
+                    // saved along with crash dumps, see set_up_crash_dumps()
+                    if custom_options.crash_dump.is_some() {
+                        se(
+                            w,
+                            "log",
+                            &[("file", "/crun-vm/console.log"), ("append", "off")],
+                        )?;
+                    }
+                    se(w, "target", &[("port", "0")])
+                })?;
+                s(w, "console", &[("type", "pty")], |w| {
+                    se(w, "target", &[("type", "serial"), ("port", "0")])
+                })?;
+
+                // TTY-like character devices, see add_serial_device()
+                for (i, dev) in mounts.serial_device.iter().enumerate() {
+                    let path = Utf8Path::new("/").join(&dev.path_in_container);
+                    s(w, "serial", &[("type", "dev")], |w| {
+                        se(w, "source", &[("path", path.as_str())])?;
+                        se(w, "target", &[("port", &(i + 1).to_string())])
+                    })?;
+                }
 
-                s(w, "disk", &[("type", typ), ("device", "disk")], |w| {
+                let mut next_dev_index = 0;
+                let mut next_dev_name = || {
+                    let i = next_dev_index;
+                    next_dev_index += 1;
+                    format!("vd{}", ('a'..='z').cycle().nth(i).unwrap())
+                };
+
+                s(w, "disk", &[("type", "file"), ("device", "disk")], |w| {
                     se(w, "target", &[("dev", &next_dev_name()), ("bus", "virtio")])?;
-                    se(w, "driver", &[("name", "qemu"), ("type", &dev.format)])?;
+                    se(
+                        w,
+                        "driver",
+                        &[("name", "qemu"), ("type", &vm_image_info.format)],
+                    )?;
+                    se(w, "source", &[("file", vm_image_info.path.as_str())])?;
+                    Ok(())
+                })?;
+
+                for (i, dev) in mounts.block_device.iter().enumerate() {
+                    let typ = if dev.is_regular_file { "file" } else { "block" };
+                    let source_attr = if dev.is_regular_file { "file" } else { "dev" };
+
+                    s(w, "disk", &[("type", typ), ("device", "disk")], |w| {
+                        se(w, "target", &[("dev", &next_dev_name()), ("bus", "virtio")])?;
+                        se(w, "driver", &[("name", "qemu"), ("type", &dev.format)])?;
+                        se(
+                            w,
+                            "source",
+                            &[(source_attr, dev.path_in_container.as_str())],
+                        )?;
+                        if dev.readonly {
+                            se(w, "readonly", &[])?;
+                        }
+                        st(w, "serial", &[], &format!("crun-vm-block-{i}"))?;
+                        Ok(())
+                    })?;
+                }
+
+                s(w, "disk", &[("type", "file"), ("device", "disk")], |w| {
                     se(
                         w,
                         "source",
-                        &[(source_attr, dev.path_in_container.as_str())],
+                        &[("file", "/crun-vm/first-boot/cloud-init.iso")],
                     )?;
-                    if dev.readonly {
-                        se(w, "readonly", &[])?;
-                    }
-                    st(w, "serial", &[], &format!("crun-vm-block-{i}"))?;
+                    se(w, "target", &[("dev", &next_dev_name()), ("bus", "virtio")])?;
                     Ok(())
                 })?;
-            }
 
-            s(w, "disk", &[("type", "file"), ("device", "disk")], |w| {
-                se(
-                    w,
-                    "source",
-                    &[("file", "/crun-vm/first-boot/cloud-init.iso")],
-                )?;
-                se(w, "target", &[("dev", &next_dev_name()), ("bus", "virtio")])?;
-                Ok(())
-            })?;
+                // adjusted to the container's actual network interfaces when the container starts
+                let interface_alias = match custom_options.network {
+                    NetworkMode::None => None,
+                    NetworkMode::Passt => Some(DEFAULT_INTERFACE_ALIAS),
+                    NetworkMode::PasstAll => Some(ALL_INTERFACES_ALIAS),
+                };
+
+                if let Some(interface_alias) = interface_alias {
+                    s(w, "interface", &[("type", "user")], |w| {
+                        se(w, "alias", &[("name", interface_alias)])?;
+                        se(w, "mac", &[("address", mac_address)])?;
+                        se(w, "backend", &[("type", "passt")])?;
+                        se(w, "model", &[("type", "virtio")])?;
+                        if custom_options.forward.is_empty() {
+                            s(w, "portForward", &[("proto", "tcp")], |w| {
+                                // leave the gdbstub's port to QEMU
+                                if let Some(GdbStub::Port(port)) = custom_options.gdb {
+                                    let port = port.to_string();
+                                    se(w, "range", &[("start", &port), ("exclude", "yes")])?;
+                                }
+                                Ok(())
+                            })?;
+                            se(w, "portForward", &[("proto", "udp")])?;
+                        }
+
+                        for forward in &custom_options.forward {
+                            if let PortForward::Range {
+                                protocol,
+                                start,
+                                end,
+                                guest_start,
+                            } = forward
+                            {
+                                s(w, "portForward", &[("proto", protocol)], |w| {
+                                    se(
+                                        w,
+                                        "range",
+                                        &[
+                                            ("start", &start.to_string()),
+                                            ("end", &end.to_string()),
+                                            ("to", &guest_start.to_string()),
+                                        ],
+                                    )
+                                })?;
+                            }
+                        }
+                        Ok(())
+                    })?;
+                }
+
+                // for exec'ing into the guest when it doesn't run an ssh server
+                s(w, "channel", &[("type", "unix")], |w| {
+                    se(
+                        w,
+                        "target",
+                        &[("type", "virtio"), ("name", "org.qemu.guest_agent.0")],
+                    )
+                })?;
+
+                if custom_options.crash_dump.is_some() {
+                    // let the guest report kernel panics, and have an unresponsive guest count as a
+                    // crash if it arms the watchdog
+                    se(w, "panic", &[])?;
+                    se(w, "watchdog", &[("model", "i6300esb"), ("action", "pause")])?;
+                }
+
+                // for the guest to report that it has finished booting, see READY_UNIT
+                if is_ready_notification_requested(spec) {
+                    s(w, "channel", &[("type", "unix")], |w| {
+                        se(
+                            w,
+                            "source",
+                            &[("mode", "bind"), ("path", "/crun-vm/ready.sock")],
+                        )?;
+                        se(
+                            w,
+                            "target",
+                            &[("type", "virtio"), ("name", "org.crun-vm.ready")],
+                        )
+                    })?;
+                }
+
+                // channels specified using --channel, see set_up_channels()
+                for (i, channel) in custom_options.channel.iter().enumerate() {
+                    let path = format!(
+                        "/crun-vm/channels/{i}/{}",
+                        channel.path.file_name().unwrap()
+                    );
+                    let name = format!("org.crun-vm.channel.{}", channel.name);
+
+                    s(w, "channel", &[("type", "unix")], |w| {
+                        se(w, "source", &[("mode", "bind"), ("path", &path)])?;
+                        se(w, "target", &[("type", "virtio"), ("name", &name)])
+                    })?;
+                }
+
+                // for exec'ing into the guest over ssh even if its network is misconfigured
+                // (the entrypoint lets exec know which CID libvirt picks)
+                if vsock {
+                    s(w, "vsock", &[("model", "virtio")], |w| {
+                        se(w, "cid", &[("auto", "yes")])
+                    })?;
+                }
+
+                for (i, mount) in mounts.virtiofs.iter().enumerate() {
+                    let path = mount.path_in_container.as_str();
+                    let tag = format!("virtiofs-{}", i);
+
+                    if mounts.share_backend == ShareBackend::NineP {
+                        // "squash" ignores failures to change file ownership, which we are likely to
+                        // hit since QEMU isn't really root
+                        s(
+                            w,
+                            "filesystem",
+                            &[("type", "mount"), ("accessmode", "squash")],
+                            |w| {
+                                se(w, "driver", &[("type", "path")])?;
+                                se(w, "source", &[("dir", path)])?;
+                                se(w, "target", &[("dir", &tag)])?;
+                                if mount.readonly {
+                                    se(w, "readonly", &[])?;
+                                }
+                                Ok(())
+                            },
+                        )?;
+                        continue;
+                    }
+
+                    // passes along the share's virtiofsd arguments, see set_up_virtiofsd()
+                    let binary = format!("/crun-vm/virtiofsd/{i}.sh");
+
+                    let queue_size = mount.queue_size.map(|q| q.to_string());
+                    let xattr = if mount.xattr { "on" } else { "off" };
+
+                    s(w, "filesystem", &[("type", "mount")], |w| {
+                        match &queue_size {
+                            Some(q) => se(w, "driver", &[("type", "virtiofs"), ("queue", q)])?,
+                            None => se(w, "driver", &[("type", "virtiofs")])?,
+                        }
+                        s(
+                            w,
+                            "binary",
+                            &[("path", binary.as_str()), ("xattr", xattr)],
+                            |w| se(w, "sandbox", &[("mode", "chroot")]),
+                        )?;
+                        se(w, "source", &[("dir", path)])?;
+                        se(w, "target", &[("dir", &tag)])?;
+                        Ok(())
+                    })?;
+                }
 
-            s(w, "interface", &[("type", "user")], |w| {
-                se(w, "backend", &[("type", "passt")])?;
-                se(w, "model", &[("type", "virtio")])?;
-                se(w, "portForward", &[("proto", "tcp")])?;
-                se(w, "portForward", &[("proto", "udp")])?;
                 Ok(())
             })?;
 
-            for (i, mount) in mounts.virtiofs.iter().enumerate() {
-                let path = mount.path_in_container.as_str();
-                let tag = format!("virtiofs-{}", i);
-
-                s(w, "filesystem", &[("type", "mount")], |w| {
-                    se(w, "driver", &[("type", "virtiofs")])?;
-                    s(
-                        w,
-                        "binary",
-                        &[("path", "/crun-vm/virtiofsd.sh"), ("xattr", "on")],
-                        |w| se(w, "sandbox", &[("mode", "chroot")]),
-                    )?;
-                    se(w, "source", &[("dir", path)])?;
-                    se(w, "target", &[("dir", &tag)])?;
+            // for what libvirt has no XML for
+            if !qemu_args.is_empty() {
+                s(w, "qemu:commandline", &[], |w| {
+                    for arg in &qemu_args {
+                        se(w, "qemu:arg", &[("value", arg)])?;
+                    }
                     Ok(())
                 })?;
             }
 
             Ok(())
-        })?;
-
-        Ok(())
-    })?;
+        },
+    )?;
 
     w.inner_mut().flush()?;
 
     Ok(())
 }
 
+const QEMU_NAMESPACE: &str = "http://libvirt.org/schemas/domain/qemu/1.0";
+
+/// Returns additional arguments to pass to QEMU.
+fn get_qemu_args(spec: &oci_spec::runtime::Spec, custom_options: &CustomOptions) -> Vec<String> {
+    let mut args = vec![];
+
+    // the container's environment variables, see set_up_guest_env()
+    if !get_guest_env(spec).is_empty() {
+        for name in ["environment", "systemd-environment"] {
+            args.push("-fw_cfg".to_string());
+            args.push(format!("name=opt/org.crun-vm/{name},file=/crun-vm/{name}"));
+        }
+    }
+
+    // an additional QMP monitor, as libvirt keeps its own to itself, see set_up_private_sockets()
+    if let Some(path) = &custom_options.qmp_socket {
+        args.push("-qmp".to_string());
+        args.push(format!(
+            "unix:/crun-vm/qmp/{},server=on,wait=off",
+            path.file_name().unwrap()
+        ));
+    }
+
+    match &custom_options.gdb {
+        Some(GdbStub::Port(port)) => {
+            args.push("-gdb".to_string());
+            args.push(format!("tcp::{port}"));
+        }
+        Some(GdbStub::Socket(path)) => {
+            args.push("-gdb".to_string());
+            args.push(format!(
+                "unix:/crun-vm/gdb/{},server=on,wait=off",
+                path.file_name().unwrap()
+            ));
+        }
+        None => {}
+    }
+
+    args
+}
+
 fn merge_overlays(
     base_path: impl AsRef<Utf8Path>,
     overlay_paths: &[impl AsRef<Utf8Path>],
@@ -208,6 +442,15 @@ fn merge_overlays(
 
     #[must_use]
     fn merge(base: &minidom::Element, overlay: &minidom::Element) -> minidom::Element {
+        // QEMU arguments are appended rather than merged with each other
+        if base.is("commandline", QEMU_NAMESPACE) {
+            let mut merged = base.clone();
+            for overlay_child in overlay.children() {
+                merged.append_child(overlay_child.clone());
+            }
+            return merged;
+        }
+
         let mut builder = minidom::Element::builder(base.name(), base.ns());
 
         for (name, val) in base.attrs().chain(overlay.attrs()) {
@@ -264,6 +507,18 @@ fn merge_overlays(
         base_root = merge(&base_root, &overlay_root);
     }
 
+    if !overlay_paths.is_empty() {
+        // merging loses namespace prefix declarations, so redeclare the one for QEMU elements
+        let mut builder = minidom::Element::builder(base_root.name(), "")
+            .prefix(Some("qemu".to_string()), QEMU_NAMESPACE)?;
+
+        for (name, val) in base_root.attrs() {
+            builder = builder.attr(name, val);
+        }
+
+        base_root = builder.append_all(base_root.nodes().cloned()).build();
+    }
+
     save(&base_path, &base_root)
 }
 
@@ -273,11 +528,25 @@ fn s(
     name: &str,
     attrs: &[(&str, &str)],
     f: impl FnOnce(&mut xml::EventWriter<File>) -> xml::writer::Result<()>,
+) -> xml::writer::Result<()> {
+    s_ns(w, name, attrs, &[], f)
+}
+
+// section declaring namespace prefixes
+fn s_ns(
+    w: &mut xml::EventWriter<File>,
+    name: &str,
+    attrs: &[(&str, &str)],
+    namespaces: &[(&str, &str)],
+    f: impl FnOnce(&mut xml::EventWriter<File>) -> xml::writer::Result<()>,
 ) -> xml::writer::Result<()> {
     let mut start = XmlEvent::start_element(name);
     for (key, value) in attrs {
         start = start.attr(*key, value);
     }
+    for (prefix, uri) in namespaces {
+        start = start.ns(*prefix, *uri);
+    }
 
     w.write(start)?;
     f(w)?;
@@ -7,6 +7,7 @@ use std::process::Command;
 use anyhow::{bail, ensure, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 
+use crate::commands::create::custom_opts::Channel;
 use crate::commands::create::Mounts;
 
 pub struct FirstBootConfig<'a> {
@@ -14,8 +15,78 @@ pub struct FirstBootConfig<'a> {
     pub container_public_key: &'a str,
     pub password: Option<&'a str>,
     pub mounts: &'a Mounts,
+    /// Whether to apply the container's environment variables on every boot, see `ENV_SCRIPT`.
+    pub apply_env: bool,
+    /// Entries to add to the guest's `/etc/hosts`.
+    pub hosts: &'a [String],
+    pub channels: &'a [Channel],
+    /// Whether to have the guest report when it has finished booting, see `READY_UNIT`.
+    pub notify_ready: bool,
 }
 
+/// Reports to the container's entrypoint that the guest has finished booting, through a
+/// virtio-serial port, so that it can notify systemd.
+const READY_UNIT_NAME: &str = "crun-vm-ready.service";
+const READY_UNIT: &str = "\
+    [Unit]\n\
+    Description=Report to crun-vm that the VM has finished booting\n\
+    After=multi-user.target\n\
+    ConditionPathExists=/dev/virtio-ports/org.crun-vm.ready\n\
+    \n\
+    [Service]\n\
+    Type=oneshot\n\
+    RemainAfterExit=yes\n\
+    ExecStart=/bin/sh -c 'echo READY > /dev/virtio-ports/org.crun-vm.ready'\n\
+    \n\
+    [Install]\n\
+    WantedBy=multi-user.target\n\
+    ";
+
+/// Applies the container's environment variables, which crun-vm passes in through fw_cfg on every
+/// boot so that they are never stored on the VM's disk, as they may come from secrets.
+///
+/// Login sessions, including the ones for podman-exec, read them through PAM from
+/// `/etc/environment`, which we turn into a link to a copy in `/run` that also holds the file's
+/// original contents. Services get them from systemd's environment.
+const ENV_SCRIPT: &str = "\
+    set -e\n\
+    modprobe qemu_fw_cfg 2>/dev/null || true\n\
+    dir=/sys/firmware/qemu_fw_cfg/by_name/opt/org.crun-vm\n\
+    [ -e \"$dir/environment/raw\" ] || exit 0\n\
+    umask 077\n\
+    mkdir -p /etc/crun-vm /run/crun-vm\n\
+    if [ ! -L /etc/environment ]; then\n\
+    if [ -e /etc/environment ]; then mv /etc/environment /etc/crun-vm/environment.orig; fi\n\
+    ln -s /run/crun-vm/environment /etc/environment\n\
+    fi\n\
+    { cat /etc/crun-vm/environment.orig 2>/dev/null || true; cat \"$dir/environment/raw\"; } \
+    >/run/crun-vm/environment\n\
+    chcon -t etc_t /run/crun-vm/environment 2>/dev/null || true\n\
+    xargs -0 -r systemctl set-environment <\"$dir/systemd-environment/raw\"\n\
+    ";
+
+const ENV_SCRIPT_PATH: &str = "/etc/crun-vm/apply-env.sh";
+
+/// Runs `ENV_SCRIPT` early on every boot, before services and login sessions start.
+const ENV_UNIT_NAME: &str = "crun-vm-env.service";
+const ENV_UNIT: &str = "\
+    [Unit]\n\
+    Description=Apply the environment variables of the crun-vm container\n\
+    DefaultDependencies=no\n\
+    After=local-fs.target systemd-modules-load.service\n\
+    Before=basic.target systemd-user-sessions.service\n\
+    \n\
+    [Service]\n\
+    Type=oneshot\n\
+    RemainAfterExit=yes\n\
+    ExecStart=/bin/sh /etc/crun-vm/apply-env.sh\n\
+    \n\
+    [Install]\n\
+    WantedBy=basic.target\n\
+    ";
+
+const HOSTS_FILE_PATH: &str = "/etc/hosts";
+
 impl FirstBootConfig<'_> {
     pub fn apply_to_cloud_init_config(
         &self,
@@ -98,16 +169,27 @@ impl FirstBootConfig<'_> {
                 _ => bail!("invalid user-data file"),
             };
 
-            let mut add_mount = |typ: &str, tag: &str, path_in_guest: &Utf8Path| {
-                mounts.push(vec![&tag, path_in_guest.as_str(), typ, "defaults", "0", "0"].into());
+            let mut add_mount = |typ: &str, tag: &str, path_in_guest: &Utf8Path, options: &str| {
+                mounts.push(vec![&tag, path_in_guest.as_str(), typ, options, "0", "0"].into());
             };
 
             for (i, mount) in self.mounts.virtiofs.iter().enumerate() {
-                add_mount("virtiofs", &format!("virtiofs-{i}"), &mount.path_in_guest);
+                let (typ, options) = self.mounts.share_type_and_options(mount);
+                add_mount(typ, &format!("virtiofs-{i}"), &mount.path_in_guest, options);
             }
 
             for mount in &self.mounts.tmpfs {
-                add_mount("tmpfs", "tmpfs", &mount.path_in_guest);
+                add_mount("tmpfs", "tmpfs", &mount.path_in_guest, &mount.options);
+            }
+
+            for mount in &self.mounts.file {
+                let options = if mount.readonly { "bind,ro" } else { "bind" };
+                add_mount(
+                    "none",
+                    mount.staged_path_in_guest.as_str(),
+                    &mount.path_in_guest,
+                    options,
+                );
             }
         }
 
@@ -150,12 +232,44 @@ impl FirstBootConfig<'_> {
             m.into()
         });
 
-        // create block device symlinks and udev rules
+        // add host name entries
+
+        if let Some(hosts) = self.get_hosts_file_addition() {
+            let mut m = serde_yaml::Mapping::new();
+            m.insert("path".into(), HOSTS_FILE_PATH.into());
+            m.insert("content".into(), hosts.into());
+            m.insert("append".into(), true.into());
+            write_files.push(m.into());
+        }
+
+        // report when the guest has finished booting
+
+        if self.notify_ready {
+            let mut m = serde_yaml::Mapping::new();
+            m.insert(
+                "path".into(),
+                format!("/etc/systemd/system/{READY_UNIT_NAME}").into(),
+            );
+            m.insert("content".into(), READY_UNIT.into());
+            write_files.push(m.into());
+        }
+
+        // create mount points for bind mounted regular files, without clobbering existing files
+
+        for mount in &self.mounts.file {
+            let mut m = serde_yaml::Mapping::new();
+            m.insert("path".into(), mount.path_in_guest.as_str().into());
+            m.insert("content".into(), "".into());
+            m.insert("append".into(), true.into());
+            write_files.push(m.into());
+        }
+
+        // create block device symlinks, and udev rules for devices and channels
 
         let block_device_symlinks = self.get_block_device_symlinks();
-        let block_device_udev_rules = self.get_block_device_udev_rules();
+        let udev_rules = self.get_udev_rules();
 
-        if let Some(rules) = &block_device_udev_rules {
+        if let Some(rules) = &udev_rules {
             let mut mapping = serde_yaml::Mapping::new();
             mapping.insert("path".into(), "/etc/udev/rules.d/99-crun-vm.rules".into());
             mapping.insert("content".into(), rules.to_string().into());
@@ -163,7 +277,7 @@ impl FirstBootConfig<'_> {
             write_files.push(mapping.into());
         }
 
-        if !block_device_symlinks.is_empty() || block_device_udev_rules.is_some() {
+        if !block_device_symlinks.is_empty() || udev_rules.is_some() {
             let runcmd = match user_data_mapping
                 .entry("runcmd".into())
                 .or_insert_with(|| serde_yaml::Value::Sequence(vec![]))
@@ -187,11 +301,48 @@ impl FirstBootConfig<'_> {
                 ]));
             }
 
-            if block_device_udev_rules.is_some() {
+            if udev_rules.is_some() {
                 runcmd.push("udevadm trigger".into());
             }
         }
 
+        if self.apply_env {
+            // bootcmd runs on every boot, before login sessions are allowed, and unlike runcmd
+            // already on the first one
+            let bootcmd = match user_data_mapping
+                .entry("bootcmd".into())
+                .or_insert_with(|| serde_yaml::Value::Sequence(vec![]))
+            {
+                serde_yaml::Value::Sequence(v) => v,
+                _ => bail!("invalid user-data file"),
+            };
+
+            bootcmd.push(serde_yaml::Value::Sequence(vec![
+                "sh".into(),
+                "-c".into(),
+                ENV_SCRIPT.into(),
+            ]));
+        }
+
+        if self.notify_ready {
+            let runcmd = match user_data_mapping
+                .entry("runcmd".into())
+                .or_insert_with(|| serde_yaml::Value::Sequence(vec![]))
+            {
+                serde_yaml::Value::Sequence(v) => v,
+                _ => bail!("invalid user-data file"),
+            };
+
+            // don't wait for it, as it is ordered after cloud-init itself
+            runcmd.push(serde_yaml::Value::Sequence(vec![
+                "systemctl".into(),
+                "enable".into(),
+                "--now".into(),
+                "--no-block".into(),
+                READY_UNIT_NAME.into(),
+            ]));
+        }
+
         // generate iso
 
         {
@@ -343,9 +494,9 @@ impl FirstBootConfig<'_> {
             }));
         }
 
-        // create block device symlinks and udev rules
+        // create block device symlinks, and udev rules for devices and channels
 
-        if let Some(rules) = self.get_block_device_udev_rules() {
+        if let Some(rules) = self.get_udev_rules() {
             files.push(serde_json::json!({
                 "path": "/etc/udev/rules.d/99-crun-vm.rules",
                 "mode": 0o644,
@@ -356,6 +507,39 @@ impl FirstBootConfig<'_> {
             }));
         }
 
+        // set environment variables
+
+        if self.apply_env {
+            files.push(serde_json::json!({
+                "path": ENV_SCRIPT_PATH,
+                "mode": 0o755,
+                "overwrite": true,
+                "contents": {
+                    "source": format!("data:,{}", urlencoding::encode(ENV_SCRIPT))
+                }
+            }));
+        }
+
+        // add host name entries
+
+        if let Some(hosts) = self.get_hosts_file_addition() {
+            files.push(serde_json::json!({
+                "path": HOSTS_FILE_PATH,
+                "append": [{
+                    "source": format!("data:,{}", urlencoding::encode(&hosts))
+                }]
+            }));
+        }
+
+        // create mount points for bind mounted regular files, without clobbering existing files
+
+        for mount in &self.mounts.file {
+            files.push(serde_json::json!({
+                "path": mount.path_in_guest.as_str(),
+                "overwrite": false,
+            }));
+        }
+
         let links = match storage
             .entry("links")
             .or_insert_with(|| serde_json::json!([]))
@@ -391,7 +575,7 @@ impl FirstBootConfig<'_> {
             _ => bail!("invalid config file"),
         };
 
-        let mut add_mount = |typ: &str, tag: &str, path_in_guest: &Utf8Path| {
+        let mut add_mount = |typ: &str, tag: &str, path_in_guest: &Utf8Path, options: &str| {
             // systemd insists on this unit file name format
             let systemd_unit_file_name = format!(
                 "{}.mount",
@@ -404,6 +588,7 @@ impl FirstBootConfig<'_> {
                 What={tag}\n\
                 Where={path_in_guest}\n\
                 Type={typ}\n\
+                Options={options}\n\
                 \n\
                 [Install]\n\
                 WantedBy=local-fs.target\n\
@@ -418,11 +603,38 @@ impl FirstBootConfig<'_> {
         };
 
         for (i, mount) in self.mounts.virtiofs.iter().enumerate() {
-            add_mount("virtiofs", &format!("virtiofs-{i}"), &mount.path_in_guest);
+            let (typ, options) = self.mounts.share_type_and_options(mount);
+            add_mount(typ, &format!("virtiofs-{i}"), &mount.path_in_guest, options);
         }
 
         for mount in &self.mounts.tmpfs {
-            add_mount("tmpfs", "tmpfs", &mount.path_in_guest);
+            add_mount("tmpfs", "tmpfs", &mount.path_in_guest, &mount.options);
+        }
+
+        for mount in &self.mounts.file {
+            let options = if mount.readonly { "bind,ro" } else { "bind" };
+            add_mount(
+                "none",
+                mount.staged_path_in_guest.as_str(),
+                &mount.path_in_guest,
+                options,
+            );
+        }
+
+        if self.apply_env {
+            units.push(serde_json::json!({
+                "name": ENV_UNIT_NAME,
+                "enabled": true,
+                "contents": ENV_UNIT
+            }));
+        }
+
+        if self.notify_ready {
+            units.push(serde_json::json!({
+                "name": READY_UNIT_NAME,
+                "enabled": true,
+                "contents": READY_UNIT
+            }));
         }
 
         // generate file
@@ -435,6 +647,14 @@ impl FirstBootConfig<'_> {
         Ok(())
     }
 
+    fn get_hosts_file_addition(&self) -> Option<String> {
+        if self.hosts.is_empty() {
+            return None;
+        }
+
+        Some(format!("\n# added by crun-vm\n{}\n", self.hosts.join("\n")))
+    }
+
     fn get_block_device_symlinks(&self) -> Vec<(&Utf8Path, Utf8PathBuf)> {
         let mut symlinks = Vec::new();
 
@@ -448,7 +668,7 @@ impl FirstBootConfig<'_> {
         symlinks
     }
 
-    fn get_block_device_udev_rules(&self) -> Option<String> {
+    fn get_udev_rules(&self) -> Option<String> {
         let mut rules = String::new();
 
         for (i, dev) in self.mounts.block_device.iter().enumerate() {
@@ -461,6 +681,22 @@ impl FirstBootConfig<'_> {
             }
         }
 
+        for (i, dev) in self.mounts.serial_device.iter().enumerate() {
+            // the first serial port is the console
+            rules.push_str(&format!(
+                "SUBSYSTEM==\"tty\", KERNEL==\"ttyS{}\", SYMLINK+=\"{}\"\n",
+                i + 1,
+                dev.path_in_guest.strip_prefix("/dev").unwrap(),
+            ));
+        }
+
+        for channel in self.channels {
+            rules.push_str(&format!(
+                "SUBSYSTEM==\"virtio-ports\", ATTR{{name}}==\"org.crun-vm.channel.{0}\", SYMLINK+=\"crun-vm/channels/{0}\"\n",
+                channel.name,
+            ));
+        }
+
         if rules.is_empty() {
             None
         } else {
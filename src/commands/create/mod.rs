@@ -5,6 +5,7 @@ mod domain;
 mod engine;
 mod first_boot;
 
+use std::env;
 use std::ffi::OsStr;
 use std::fs::{self, File, Permissions};
 use std::io::ErrorKind;
@@ -14,15 +15,19 @@ use std::process::{Command, Stdio};
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
 use lazy_static::lazy_static;
 use nix::sys::stat::{major, makedev, minor, mknod, Mode, SFlag};
 use regex::Regex;
 use rust_embed::RustEmbed;
 
-use crate::commands::create::custom_opts::CustomOptions;
+use crate::commands::create::custom_opts::{
+    CustomOptions, GdbStub, NetworkMode, ShareBackend, VirtiofsOptions,
+};
 use crate::commands::create::domain::set_up_libvirt_domain_xml;
 use crate::commands::create::engine::Engine;
 use crate::commands::create::first_boot::FirstBootConfig;
+use crate::commands::exec::build_command;
 use crate::util::{
     bind_mount_dir_with_different_context, bind_mount_file, create_overlay_vm_image, crun,
     find_single_file_in_dirs, fix_selinux_label, is_mountpoint, set_file_context, SpecExt,
@@ -75,8 +80,15 @@ pub fn create(args: &liboci_cli::Create, raw_args: &[impl AsRef<OsStr>]) -> Resu
     set_up_mounts(&mut spec, &mut mounts)?;
     set_up_devices(&mut spec, &mut mounts)?;
     set_up_blockdevs(&mut spec, &mut mounts, &custom_options)?;
-
-    set_up_extra_container_mounts_and_devices(&mut spec, &custom_options)?;
+    set_up_virtiofsd(&spec, &mut mounts, &custom_options)?;
+    set_up_channels(&mut spec, &custom_options)?;
+    set_up_private_sockets(&mut spec, &custom_options)?;
+    set_up_crash_dumps(&mut spec, &custom_options)?;
+    set_up_guest_env(&spec)?;
+
+    let vsock = Path::new("/dev/vhost-vsock").try_exists()?;
+    let mac_address = get_mac_address(&spec, &args.container_id, &custom_options);
+    set_up_extra_container_mounts_and_devices(&mut spec, &custom_options, vsock)?;
     set_up_security(&mut spec);
 
     let ssh_pub_key = set_up_ssh_key_pair(
@@ -89,10 +101,17 @@ pub fn create(args: &liboci_cli::Create, raw_args: &[impl AsRef<OsStr>]) -> Resu
 
     if is_first_create {
         set_up_first_boot_config(&spec, &mounts, &custom_options, &ssh_pub_key)?;
-        set_up_libvirt_domain_xml(&spec, &base_vm_image_info, &mounts, &custom_options)?;
+        set_up_libvirt_domain_xml(
+            &spec,
+            &base_vm_image_info,
+            &mounts,
+            &custom_options,
+            vsock,
+            &mac_address,
+        )?;
     }
 
-    adjust_container_rlimits_and_resources(&mut spec);
+    adjust_container_rlimits_and_resources(&mut spec, &custom_options);
 
     spec.save(&config_path)?;
     spec.save(spec.root_path()?.join("crun-vm/config.json"))?; // to aid debugging
@@ -216,10 +235,16 @@ fn set_up_container_root(
     let new_root_path = priv_dir_path.join("root");
     fs::create_dir_all(&new_root_path)?;
 
-    match fs::remove_file(new_root_path.join("crun-vm/ssh-successful")) {
-        Err(e) if e.kind() == ErrorKind::NotFound => {}
-        r => r?,
-    };
+    for file in [
+        "crun-vm/guest-addresses",
+        "crun-vm/ssh-successful",
+        "crun-vm/vsock-cid",
+    ] {
+        match fs::remove_file(new_root_path.join(file)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            r => r?,
+        };
+    }
 
     // create root directory
 
@@ -260,12 +285,34 @@ fn set_up_container_root(
         vec!["/crun-vm/entrypoint.sh", arg]
     };
 
+    let mut command: Vec<String> = command.into_iter().map(String::from).collect();
+
+    if let Some(run) = &custom_options.run {
+        // one-shot mode: have the entrypoint run the command in the guest through the same path as
+        // podman-exec, and then power the guest off and exit with the command's status
+        let user = custom_options.run_as.as_deref().unwrap_or("root");
+        let mut exec_args = vec!["--as".to_string(), user.to_string()];
+        if let Some(timeout) = custom_options.run_timeout {
+            exec_args.extend(["--timeout".to_string(), timeout.to_string()]);
+        }
+        exec_args.push(run.clone());
+        command.extend(build_command(&exec_args)?);
+    }
+
+    // our entrypoint runs in the root directory, so remember the container's actual working
+    // directory for exec to tell whether another one was requested
+    let original_cwd = spec.process().as_ref().unwrap().cwd();
+    fs::write(
+        new_root_path.join("crun-vm/original-cwd"),
+        original_cwd.as_os_str().as_encoded_bytes(),
+    )?;
+
     spec.set_process({
         let mut process = spec.process().clone().unwrap();
 
         process.set_cwd(".".into());
         process.set_command_line(None);
-        process.set_args(Some(command.into_iter().map(String::from).collect()));
+        process.set_args(Some(command));
 
         fix_selinux_label(&mut process);
 
@@ -381,11 +428,30 @@ fn set_up_vm_image(
     }
 }
 
+/// Where the virtiofs share holding bind mounted regular files is mounted in the guest.
+const STAGED_FILES_PATH_IN_GUEST: &str = "/run/crun-vm/files";
+
 #[derive(Default)]
 struct Mounts {
+    share_backend: ShareBackend,
+    /// Directories shared with the guest, through virtiofs or 9p depending on `share_backend`.
     virtiofs: Vec<VirtiofsMount>,
     tmpfs: Vec<TmpfsMount>,
     block_device: Vec<BlockDeviceMount>,
+    serial_device: Vec<SerialDeviceMount>,
+    file: Vec<FileMount>,
+}
+
+impl Mounts {
+    /// Returns the file system type and mount options to use for a directory share in the guest.
+    fn share_type_and_options(&self, mount: &VirtiofsMount) -> (&'static str, &'static str) {
+        match (self.share_backend, mount.readonly) {
+            (ShareBackend::Virtiofs, false) => ("virtiofs", "defaults"),
+            (ShareBackend::Virtiofs, true) => ("virtiofs", "ro"),
+            (ShareBackend::NineP, false) => ("9p", "trans=virtio,version=9p2000.L"),
+            (ShareBackend::NineP, true) => ("9p", "trans=virtio,version=9p2000.L,ro"),
+        }
+    }
 }
 
 struct BlockDeviceMount {
@@ -396,23 +462,44 @@ struct BlockDeviceMount {
     readonly: bool,
 }
 
+/// A TTY-like character device exposed to the guest as a serial port.
+struct SerialDeviceMount {
+    path_in_container: Utf8PathBuf,
+    path_in_guest: Utf8PathBuf,
+}
+
+/// The guest's first serial port is its console, and QEMU only emulates a few more ISA ones.
+const MAX_SERIAL_DEVICES: usize = 3;
+
 struct VirtiofsMount {
     path_in_container: Utf8PathBuf,
     path_in_guest: Utf8PathBuf,
+    readonly: bool,
+    /// Additional arguments to pass to the share's virtiofsd.
+    virtiofsd_args: Vec<String>,
+    xattr: bool,
+    queue_size: Option<u32>,
 }
 
 struct TmpfsMount {
     path_in_guest: Utf8PathBuf,
+    /// Comma-separated tmpfs mount options to use in the guest.
+    options: String,
+}
+
+struct FileMount {
+    staged_path_in_guest: Utf8PathBuf,
+    path_in_guest: Utf8PathBuf,
+    readonly: bool,
 }
 
 fn set_up_mounts(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Result<()> {
     const TARGETS_TO_IGNORE: &[&str] = &[
         "/etc/hostname",
-        "/etc/hosts",
-        "/etc/resolv.conf",
+        "/etc/hosts",       // its entries are added to the guest's own on first boot
+        "/etc/resolv.conf", // passt advertises the nameservers and search domains in it over DHCP
         "/proc",
         "/run/.containerenv",
-        "/run/secrets",
         "/sys",
         "/sys/fs/cgroup",
     ];
@@ -428,7 +515,24 @@ fn set_up_mounts(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Res
             continue;
         }
 
-        match oci_mount.typ().as_deref() {
+        let has_option = |option: &str| oci_mount.options().iter().flatten().any(|o| o == option);
+
+        // Podman gives secrets as a bind mounted directory, which we expose to the guest like any
+        // other directory but never let it modify
+        let is_secrets_dir = oci_mount.destination() == Path::new("/run/secrets");
+
+        let readonly = has_option("ro") || has_option("readonly") || is_secrets_dir;
+
+        // Podman and Docker usually give named volumes and image volumes as bind mounts, but the OCI
+        // spec also allows bind mounts to be specified through their options alone.
+        let source_is_path = oci_mount.source().as_ref().is_some_and(|s| s.is_absolute());
+        let typ = match oci_mount.typ().as_deref() {
+            None | Some("none") if has_option("bind") || has_option("rbind") => Some("bind"),
+            Some("volume") if source_is_path => Some("bind"),
+            typ => typ,
+        };
+
+        match typ {
             Some("bind") => {
                 let meta = oci_mount.source().as_ref().unwrap().metadata()?;
 
@@ -440,23 +544,24 @@ fn set_up_mounts(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Res
                         continue;
                     }
 
-                    path_in_container = Utf8PathBuf::from(format!(
-                        "/crun-vm/mounts/virtiofs/{}",
-                        mounts.virtiofs.len()
-                    ));
-                    let path_in_guest = oci_mount.destination().clone().try_into()?;
+                    path_in_container = add_virtiofs_mount(mounts, oci_mount, readonly)?;
+                } else if meta.file_type().is_file() && !oci_mount.destination().starts_with("/dev")
+                {
+                    // Expose regular files through a virtiofs share holding all of them, and bind
+                    // mount each to its destination in the guest. Files bound for /dev are
+                    // instead exposed as block devices, as are files given using --blockdev.
 
-                    mounts.virtiofs.push(VirtiofsMount {
-                        path_in_container: path_in_container.clone(),
-                        path_in_guest,
+                    let index = mounts.file.len();
+
+                    path_in_container = Utf8PathBuf::from(format!("/crun-vm/mounts/files/{index}"));
+
+                    mounts.file.push(FileMount {
+                        staged_path_in_guest: Utf8Path::new(STAGED_FILES_PATH_IN_GUEST)
+                            .join(index.to_string()),
+                        path_in_guest: oci_mount.destination().clone().try_into()?,
+                        readonly,
                     });
                 } else if meta.file_type().is_block_device() || meta.file_type().is_file() {
-                    let readonly = oci_mount
-                        .options()
-                        .iter()
-                        .flatten()
-                        .any(|o| o == "ro" || o == "readonly");
-
                     path_in_container = Utf8PathBuf::from(format!(
                         "crun-vm/mounts/block/{}",
                         mounts.block_device.len()
@@ -470,8 +575,12 @@ fn set_up_mounts(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Res
                         path_in_guest,
                         readonly,
                     });
+                } else if meta.file_type().is_char_device() && is_tty_like(meta.rdev()) {
+                    path_in_container = add_serial_device(mounts, oci_mount.destination())?;
                 } else {
-                    bail!("can only bind mount regular files, directories, and block devices");
+                    bail!(
+                        "can only bind mount regular files, directories, block devices, and TTY-like character devices"
+                    );
                 }
 
                 // redirect the mount to a path in the container that we control
@@ -479,6 +588,20 @@ fn set_up_mounts(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Res
                 new_mount.set_destination(path_in_container.as_std_path().to_path_buf());
                 new_oci_mounts.push(new_mount);
             }
+            Some("overlay") => {
+                // e.g., Podman's :O volumes
+                if oci_mount.destination().starts_with("/dev") {
+                    new_oci_mounts.push(oci_mount.clone());
+                    continue;
+                }
+
+                // have the engine set up the overlay in the container, and expose it to the guest
+                let path_in_container = add_virtiofs_mount(mounts, oci_mount, readonly)?;
+
+                let mut new_mount = oci_mount.clone();
+                new_mount.set_destination(path_in_container.as_std_path().to_path_buf());
+                new_oci_mounts.push(new_mount);
+            }
             Some("tmpfs") => {
                 if oci_mount.destination().starts_with("/dev") {
                     new_oci_mounts.push(oci_mount.clone());
@@ -487,8 +610,13 @@ fn set_up_mounts(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Res
 
                 // don't actually mount it in the container
 
-                let path_in_guest = oci_mount.destination().clone().try_into()?;
-                mounts.tmpfs.push(TmpfsMount { path_in_guest });
+                let path_in_guest: Utf8PathBuf = oci_mount.destination().clone().try_into()?;
+                let options = get_guest_tmpfs_options(oci_mount, &path_in_guest)?;
+
+                mounts.tmpfs.push(TmpfsMount {
+                    path_in_guest,
+                    options,
+                });
             }
             _ => {
                 new_oci_mounts.push(oci_mount.clone());
@@ -496,24 +624,151 @@ fn set_up_mounts(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Res
         }
     }
 
+    if !mounts.file.is_empty() {
+        mounts.virtiofs.push(VirtiofsMount {
+            path_in_container: Utf8PathBuf::from("/crun-vm/mounts/files"),
+            path_in_guest: Utf8PathBuf::from(STAGED_FILES_PATH_IN_GUEST),
+            readonly: false, // each file's bind mount may still be read-only
+            virtiofsd_args: vec![],
+            xattr: true,
+            queue_size: None,
+        });
+    }
+
     spec.set_mounts(Some(new_oci_mounts));
 
     Ok(())
 }
 
-fn set_up_devices(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Result<()> {
-    // set up block devices passed in using --device (note that rootless podman will turn those into
-    // --mount/--volume instead)
+/// Translate the OCI options of a tmpfs mount into options for mounting it in the guest.
+fn get_guest_tmpfs_options(
+    oci_mount: &oci_spec::runtime::Mount,
+    path_in_guest: &Utf8Path,
+) -> Result<String> {
+    // options that only concern how the engine sets up the mount in the container
+    const OPTIONS_TO_IGNORE: &[&str] = &[
+        "notmpcopyup",
+        "private",
+        "rprivate",
+        "rshared",
+        "rslave",
+        "runbindable",
+        "shared",
+        "slave",
+        "tmpcopyup",
+        "unbindable",
+    ];
 
-    for device in spec.linux_devices() {
-        if device.typ() != oci_spec::runtime::LinuxDeviceType::B {
+    const OPTIONS_TO_PRESERVE: &[&str] = &[
+        "async",
+        "atime",
+        "dev",
+        "diratime",
+        "exec",
+        "noatime",
+        "nodev",
+        "nodiratime",
+        "noexec",
+        "norelatime",
+        "nosuid",
+        "relatime",
+        "ro",
+        "rw",
+        "strictatime",
+        "suid",
+        "sync",
+    ];
+
+    const OPTIONS_WITH_VALUE_TO_PRESERVE: &[&str] = &[
+        "gid",
+        "huge",
+        "mode",
+        "nr_blocks",
+        "nr_inodes",
+        "size",
+        "uid",
+    ];
+
+    let mut options = vec![];
+
+    for option in oci_mount.options().iter().flatten() {
+        let name = option.split_once('=').map_or(option.as_str(), |(n, _)| n);
+
+        if OPTIONS_TO_IGNORE.contains(&option.as_str()) {
             continue;
         }
 
+        ensure!(
+            OPTIONS_TO_PRESERVE.contains(&option.as_str())
+                || (option.contains('=') && OPTIONS_WITH_VALUE_TO_PRESERVE.contains(&name)),
+            "tmpfs option '{option}' of mount at {path_in_guest} is not supported"
+        );
+
+        options.push(option.as_str());
+    }
+
+    if options.is_empty() {
+        Ok("defaults".to_string())
+    } else {
+        Ok(options.join(","))
+    }
+}
+
+/// Returns the path in the container where the directory to be exposed must be mounted.
+fn add_virtiofs_mount(
+    mounts: &mut Mounts,
+    oci_mount: &oci_spec::runtime::Mount,
+    readonly: bool,
+) -> Result<Utf8PathBuf> {
+    let path_in_container = Utf8PathBuf::from(format!(
+        "/crun-vm/mounts/virtiofs/{}",
+        mounts.virtiofs.len()
+    ));
+
+    mounts.virtiofs.push(VirtiofsMount {
+        path_in_container: path_in_container.clone(),
+        path_in_guest: oci_mount.destination().clone().try_into()?,
+        readonly,
+        virtiofsd_args: vec![],
+        xattr: true,
+        queue_size: None,
+    });
+
+    Ok(path_in_container)
+}
+
+fn set_up_devices(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Result<()> {
+    // set up block devices and TTY-like character devices passed in using --device (note that
+    // rootless podman will turn those into --mount/--volume instead)
+
+    for device in spec.linux_devices() {
         let major: u64 = device.major().try_into().unwrap();
         let minor: u64 = device.minor().try_into().unwrap();
         let mode = device.file_mode().unwrap();
 
+        if matches!(
+            device.typ(),
+            oci_spec::runtime::LinuxDeviceType::C | oci_spec::runtime::LinuxDeviceType::U
+        ) && is_tty_like(makedev(major, minor))
+        {
+            let path_in_container = add_serial_device(mounts, device.path())?;
+
+            fs::create_dir_all(spec.root_path()?.join(&path_in_container).parent().unwrap())?;
+
+            mknod(
+                spec.root_path()?.join(&path_in_container).as_std_path(),
+                SFlag::S_IFCHR,
+                Mode::from_bits_retain(mode),
+                makedev(major, minor),
+            )?;
+
+            continue;
+        }
+
+        if device.typ() != oci_spec::runtime::LinuxDeviceType::B {
+            continue;
+        }
+
         let path_in_container = Utf8PathBuf::from(format!(
             "crun-vm/mounts/block/{}",
             mounts.block_device.len()
@@ -541,6 +796,241 @@ fn set_up_devices(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Re
     Ok(())
 }
 
+/// Whether the character device with the given device number is a serial port or pseudo-terminal,
+/// as opposed to, *e.g.*, a virtual console or /dev/tty itself.
+fn is_tty_like(dev: u64) -> bool {
+    match major(dev) {
+        3 => true,             // legacy BSD pseudo-terminal secondaries
+        4 => minor(dev) >= 64, // ttyS*, as minors below are virtual consoles
+        136..=143 => true,     // Unix98 pseudo-terminal secondaries, i.e., /dev/pts/*
+        166 => true,           // ttyACM*
+        188 => true,           // ttyUSB*
+        204 => true,           // assorted other serial ports, e.g., ttyAMA*
+        _ => false,
+    }
+}
+
+/// Registers a TTY-like character device to be exposed to the guest as a serial port at the given
+/// path, and returns the path in the container where its device node should be made available.
+fn add_serial_device(mounts: &mut Mounts, path: &Path) -> Result<Utf8PathBuf> {
+    let path_in_guest: Utf8PathBuf = path.to_path_buf().try_into()?;
+
+    ensure!(
+        path_in_guest.starts_with("/dev") && !path_in_guest.starts_with("/dev/pts"),
+        "character device {path_in_guest} must be given a path in the VM under /dev but outside /dev/pts, e.g., --device {path_in_guest}:/dev/ttyharness"
+    );
+
+    lazy_static! {
+        static ref RESERVED_PATTERN: Regex = Regex::new(r"^/dev/(console|tty|ttyS?\d+)$").unwrap();
+    }
+
+    ensure!(
+        !RESERVED_PATTERN.is_match(path_in_guest.as_str()),
+        "character device path {path_in_guest} is already in use in the VM, give it a different path, e.g., --device {path_in_guest}:/dev/ttyharness"
+    );
+
+    ensure!(
+        mounts.serial_device.len() < MAX_SERIAL_DEVICES,
+        "at most {MAX_SERIAL_DEVICES} TTY-like character devices can be passed to the VM"
+    );
+
+    let path_in_container = Utf8PathBuf::from(format!(
+        "crun-vm/mounts/serial/{}",
+        mounts.serial_device.len()
+    ));
+
+    mounts.serial_device.push(SerialDeviceMount {
+        path_in_container: path_in_container.clone(),
+        path_in_guest,
+    });
+
+    Ok(path_in_container)
+}
+
+fn set_up_virtiofsd(
+    spec: &oci_spec::runtime::Spec,
+    mounts: &mut Mounts,
+    custom_options: &CustomOptions,
+) -> Result<()> {
+    mounts.share_backend = match custom_options.share_backend {
+        Some(backend) => backend,
+        None if Path::new(VIRTIOFSD_PATH).exists() => ShareBackend::Virtiofs,
+        None => ShareBackend::NineP,
+    };
+
+    if mounts.share_backend == ShareBackend::NineP {
+        ensure!(
+            custom_options.virtiofs.is_empty()
+                && custom_options.virtiofs_uid_map.is_empty()
+                && custom_options.virtiofs_gid_map.is_empty(),
+            "--virtiofs, --virtiofs-uid-map, and --virtiofs-gid-map can't be used when sharing directories with the VM over 9p{}",
+            match custom_options.share_backend {
+                Some(_) => "",
+                None => ", as is the case because virtiofsd isn't installed",
+            }
+        );
+        return Ok(());
+    }
+
+    let mut virtiofsd_help = None;
+    let mut virtiofsd_supports = |flag: &str| -> Result<bool> {
+        let help = match &virtiofsd_help {
+            Some(help) => help,
+            None => virtiofsd_help.insert(get_virtiofsd_help()?),
+        };
+        Ok(help.contains(flag))
+    };
+
+    // apply UID/GID mappings specified using --virtiofs-uid-map and --virtiofs-gid-map
+
+    let id_maps = [
+        (
+            "--translate-uid",
+            "--virtiofs-uid-map",
+            &custom_options.virtiofs_uid_map,
+        ),
+        (
+            "--translate-gid",
+            "--virtiofs-gid-map",
+            &custom_options.virtiofs_gid_map,
+        ),
+    ];
+
+    for (flag, option, maps) in id_maps {
+        ensure!(
+            maps.is_empty() || virtiofsd_supports(flag)?,
+            "{option} requires virtiofsd 1.11 or later, but the installed virtiofsd doesn't support {flag}"
+        );
+
+        for target in maps.iter().filter_map(|m| m.target.as_ref()) {
+            ensure!(
+                mounts.virtiofs.iter().any(|m| &m.path_in_guest == target),
+                "no directory is mounted at {target} in the VM"
+            );
+        }
+    }
+
+    // apply tuning options specified using --virtiofs, with the ones for a specific share taking
+    // precedence over the ones for all shares
+
+    for target in custom_options
+        .virtiofs
+        .iter()
+        .filter_map(|o| o.target.as_ref())
+    {
+        ensure!(
+            mounts.virtiofs.iter().any(|m| &m.path_in_guest == target),
+            "no directory is mounted at {target} in the VM"
+        );
+    }
+
+    for mount in &mut mounts.virtiofs {
+        if mount.readonly {
+            if virtiofsd_supports("--readonly")? {
+                mount.virtiofsd_args.push("--readonly".to_string());
+            } else {
+                // the guest still mounts the share read-only, as it did before virtiofsd supported
+                // this
+                eprintln!(
+                    "crun-vm: warning: the installed virtiofsd doesn't support --readonly, so the directory mounted at {} in the VM is only read-only from the guest's side",
+                    mount.path_in_guest
+                );
+            }
+        }
+
+        let options = custom_options
+            .virtiofs
+            .iter()
+            .filter(|o| o.target.is_none() || o.target.as_ref() == Some(&mount.path_in_guest))
+            .fold(VirtiofsOptions::default(), |acc, o| acc.merged_with(o));
+
+        let mut tuning_args = vec![];
+
+        if let Some(cache) = &options.cache {
+            tuning_args.push(format!("--cache={cache}"));
+        }
+        if options.posix_acl == Some(true) {
+            ensure!(
+                options.xattr != Some(false),
+                "--virtiofs posix-acl=on requires xattr=on for the directory mounted at {} in the VM",
+                mount.path_in_guest
+            );
+            tuning_args.push("--posix-acl".to_string());
+        }
+        if let Some(size) = options.thread_pool_size {
+            tuning_args.push(format!("--thread-pool-size={size}"));
+        }
+
+        for arg in &tuning_args {
+            let flag = arg.split('=').next().unwrap();
+            ensure!(
+                virtiofsd_supports(flag)?,
+                "the installed virtiofsd doesn't support {flag}, required by --virtiofs"
+            );
+        }
+
+        mount.virtiofsd_args.extend(tuning_args);
+        mount.xattr = options.xattr.unwrap_or(true);
+        mount.queue_size = options.queue_size;
+
+        for (flag, _, maps) in id_maps {
+            // mappings for a specific share replace the ones for all shares
+            let mut maps_for_mount: Vec<_> = maps
+                .iter()
+                .filter(|m| m.target.as_ref() == Some(&mount.path_in_guest))
+                .collect();
+
+            if maps_for_mount.is_empty() {
+                maps_for_mount = maps.iter().filter(|m| m.target.is_none()).collect();
+            }
+
+            for map in maps_for_mount {
+                mount.virtiofsd_args.push(format!(
+                    "{flag}=map:{}:{}:{}",
+                    map.guest_id, map.container_id, map.count
+                ));
+            }
+        }
+    }
+
+    // libvirt can't pass arbitrary arguments to virtiofsd, so we generate a script for each share
+    // and tell libvirt to use it instead
+
+    let scripts_dir = spec.root_path()?.join("crun-vm/virtiofsd");
+    fs::create_dir_all(&scripts_dir)?;
+
+    for (i, mount) in mounts.virtiofs.iter().enumerate() {
+        let args: String = mount
+            .virtiofsd_args
+            .iter()
+            .map(|a| format!("'{}' ", a.replace('\'', "'\\''")))
+            .collect();
+
+        let script_path = scripts_dir.join(format!("{i}.sh"));
+        fs::write(
+            &script_path,
+            format!("#!/bin/bash\nexec /crun-vm/virtiofsd.sh {args}\"$@\"\n"),
+        )?;
+        fs::set_permissions(&script_path, Permissions::from_mode(0o755))?;
+    }
+
+    Ok(())
+}
+
+const VIRTIOFSD_PATH: &str = "/usr/libexec/virtiofsd";
+
+fn get_virtiofsd_help() -> Result<String> {
+    let output = Command::new(VIRTIOFSD_PATH)
+        .arg("--help")
+        .stdin(Stdio::null())
+        .output()
+        .context("failed to run virtiofsd")?;
+
+    ensure!(output.status.success(), "failed to run virtiofsd --help");
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
 fn set_up_blockdevs(
     spec: &mut oci_spec::runtime::Spec,
     mounts: &mut Mounts,
@@ -587,9 +1077,184 @@ fn set_up_blockdevs(
     Ok(())
 }
 
+fn set_up_channels(
+    spec: &mut oci_spec::runtime::Spec,
+    custom_options: &CustomOptions,
+) -> Result<()> {
+    // QEMU creates each channel's socket once the VM starts, so we mount its parent directory from
+    // the host to the container rather than the socket itself
+
+    for (i, channel) in custom_options.channel.iter().enumerate() {
+        let dir = channel.path.parent().unwrap();
+        ensure!(
+            dir.is_dir(),
+            "--channel socket directory {} doesn't exist",
+            dir
+        );
+
+        let path_in_container = Utf8PathBuf::from(format!("crun-vm/channels/{i}"));
+        fs::create_dir_all(spec.root_path()?.join(&path_in_container))?;
+
+        spec.mounts_push(
+            oci_spec::runtime::MountBuilder::default()
+                .typ("bind")
+                .source(dir.canonicalize()?)
+                .destination(&path_in_container)
+                .options(["bind".to_string(), "rprivate".to_string()])
+                .build()
+                .unwrap(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Sets up the sockets that host tools use to control or debug the VM, which unlike channels are
+/// only accessible by the user running the container (see qemu.sh).
+fn set_up_private_sockets(
+    spec: &mut oci_spec::runtime::Spec,
+    custom_options: &CustomOptions,
+) -> Result<()> {
+    let gdb_socket = match &custom_options.gdb {
+        Some(GdbStub::Socket(path)) => Some(path),
+        _ => None,
+    };
+
+    let sockets = [
+        ("--qmp-socket", &custom_options.qmp_socket, "crun-vm/qmp"),
+        ("--gdb", &gdb_socket.cloned(), "crun-vm/gdb"),
+    ];
+
+    for (option, path, dir_in_container) in sockets {
+        let Some(path) = path else {
+            continue;
+        };
+
+        // like for channels, mount the socket's parent directory since QEMU creates the socket
+        let dir = path.parent().unwrap();
+        ensure!(
+            dir.is_dir(),
+            "{option} socket directory {dir} doesn't exist"
+        );
+
+        fs::create_dir_all(spec.root_path()?.join(dir_in_container))?;
+
+        spec.mounts_push(
+            oci_spec::runtime::MountBuilder::default()
+                .typ("bind")
+                .source(dir.canonicalize()?)
+                .destination(dir_in_container)
+                .options(["bind".to_string(), "rprivate".to_string()])
+                .build()
+                .unwrap(),
+        );
+    }
+
+    // let the entrypoint know whether to wait for a debugger before running the VM
+    let start_paused_path = spec.root_path()?.join("crun-vm/start-paused");
+    if custom_options.gdb_wait {
+        File::create(start_paused_path)?;
+    } else if start_paused_path.try_exists()? {
+        fs::remove_file(start_paused_path)?;
+    }
+
+    Ok(())
+}
+
+fn set_up_crash_dumps(
+    spec: &mut oci_spec::runtime::Spec,
+    custom_options: &CustomOptions,
+) -> Result<()> {
+    let Some(dir) = &custom_options.crash_dump else {
+        return Ok(());
+    };
+
+    ensure!(dir.is_dir(), "--crash-dump directory {dir} doesn't exist");
+
+    fs::create_dir_all(spec.root_path()?.join("crun-vm/crash-dumps"))?;
+
+    spec.mounts_push(
+        oci_spec::runtime::MountBuilder::default()
+            .typ("bind")
+            .source(dir.canonicalize()?)
+            .destination("crun-vm/crash-dumps")
+            .options(["bind".to_string(), "rprivate".to_string()])
+            .build()
+            .unwrap(),
+    );
+
+    // let the entrypoint know to watch for crashes, and how to dump the guest's memory
+    let format = custom_options
+        .crash_dump_format
+        .to_possible_value()
+        .unwrap();
+    fs::write(
+        spec.root_path()?.join("crun-vm/crash-dump-format"),
+        format.get_name(),
+    )?;
+
+    Ok(())
+}
+
+/// Whether the engine expects the container to notify systemd once it is ready, as with Podman's
+/// `--sdnotify=container`.
+///
+/// The socket is either given in the container's environment, or crun relays it into the container
+/// if given in ours.
+fn is_ready_notification_requested(spec: &oci_spec::runtime::Spec) -> bool {
+    let in_spec = spec
+        .process()
+        .as_ref()
+        .and_then(|p| p.env().as_ref())
+        .is_some_and(|env| env.iter().any(|var| var.starts_with("NOTIFY_SOCKET=")));
+
+    in_spec || env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+/// Returns the MAC address to give the guest's network interface.
+///
+/// Unless overridden, this is derived from the container's Kubernetes identity if any, as it
+/// survives the container being recreated, and from the container ID otherwise.
+fn get_mac_address(
+    spec: &oci_spec::runtime::Spec,
+    container_id: &str,
+    custom_options: &CustomOptions,
+) -> String {
+    if let Some(mac_address) = &custom_options.mac_address {
+        return mac_address.clone();
+    }
+
+    let annotation = |key: &str| {
+        spec.annotations()
+            .as_ref()
+            .and_then(|a| a.get(key))
+            .map(String::as_str)
+    };
+
+    let identity = match (
+        annotation("io.kubernetes.pod.namespace"),
+        annotation("io.kubernetes.pod.name"),
+        annotation("io.kubernetes.container.name"),
+    ) {
+        (Some(namespace), Some(pod), Some(container)) => format!("{namespace}/{pod}/{container}"),
+        _ => container_id.to_string(),
+    };
+
+    // 52:54 is a locally administered unicast prefix, commonly used for QEMU VMs
+    let [a, b, c, d] = fnv1a(&identity).to_be_bytes();
+    format!("52:54:{a:02x}:{b:02x}:{c:02x}:{d:02x}")
+}
+
+fn fnv1a(s: &str) -> u32 {
+    s.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    })
+}
+
 fn set_up_extra_container_mounts_and_devices(
     spec: &mut oci_spec::runtime::Spec,
     custom_options: &CustomOptions,
+    vsock: bool,
 ) -> Result<()> {
     fn add_bind_mount(spec: &mut oci_spec::runtime::Spec, path: impl AsRef<Path>) {
         spec.mounts_push(
@@ -641,6 +1306,18 @@ fn set_up_extra_container_mounts_and_devices(
             .unwrap(),
     );
 
+    // make our own binary available in the container, for the helpers under `crun-vm
+    // in-container`
+    spec.mounts_push(
+        oci_spec::runtime::MountBuilder::default()
+            .typ("bind")
+            .source(env::current_exe()?)
+            .destination("/crun-vm/crun-vm")
+            .options(["bind".to_string(), "rprivate".to_string(), "ro".to_string()])
+            .build()
+            .unwrap(),
+    );
+
     if !custom_options.emulated {
         ensure!(
             Path::new("/dev/kvm").try_exists()?,
@@ -651,6 +1328,11 @@ fn set_up_extra_container_mounts_and_devices(
         add_char_dev(spec, "/dev/kvm")?;
     }
 
+    if vsock {
+        add_bind_mount(spec, "/dev/vhost-vsock");
+        add_char_dev(spec, "/dev/vhost-vsock")?;
+    }
+
     // in case user sets up VFIO passthrough by overriding the libvirt XML
     for entry in fs::read_dir("/dev/vfio")? {
         let entry = entry?;
@@ -693,11 +1375,17 @@ fn set_up_first_boot_config(
     custom_options: &CustomOptions,
     container_public_key: &str,
 ) -> Result<()> {
+    let hosts = get_guest_hosts(spec)?;
+
     let config = FirstBootConfig {
         hostname: spec.hostname().as_deref(),
         container_public_key,
         password: custom_options.password.as_deref(),
         mounts,
+        apply_env: !get_guest_env(spec).is_empty(),
+        hosts: &hosts,
+        channels: &custom_options.channel,
+        notify_ready: is_ready_notification_requested(spec),
     };
 
     config
@@ -718,6 +1406,126 @@ fn set_up_first_boot_config(
     Ok(())
 }
 
+/// Writes the container's environment variables that should also be set in the guest to files that
+/// QEMU exposes to it over fw_cfg, see `get_qemu_args()`.
+///
+/// Unlike first-boot configuration, these are never stored on the guest's disk, which matters
+/// because variables may come from secrets, *e.g.*, with podman-run's `--secret type=env`.
+fn set_up_guest_env(spec: &oci_spec::runtime::Spec) -> Result<()> {
+    let env = get_guest_env(spec);
+
+    // for pam_env, which doesn't support escaping but strips a leading quote up to the next
+    // matching one, so values starting with a quote are wrapped in the other kind of quote
+    let environment: String = env
+        .iter()
+        .map(|(name, value)| match value.chars().next() {
+            Some('"') => format!("\n{name}='{value}'"),
+            Some('\'') => format!("\n{name}=\"{value}\""),
+            _ => format!("\n{name}={value}"),
+        })
+        .collect::<String>()
+        + "\n";
+
+    // for systemctl set-environment, through xargs
+    let systemd_environment: String = env
+        .iter()
+        .map(|(name, value)| format!("{name}={value}\0"))
+        .collect();
+
+    for (file, contents) in [
+        ("crun-vm/environment", environment),
+        ("crun-vm/systemd-environment", systemd_environment),
+    ] {
+        let path = spec.root_path()?.join(file);
+        fs::write(&path, contents)?;
+        fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the container's environment variables that should also be set in the guest.
+///
+/// Variables that can't be represented in the guest's `/etc/environment` are skipped rather than
+/// failing the container: those whose names aren't valid shell identifiers, those with multi-line
+/// values (*e.g.*, a PEM-encoded secret), and those whose values start with a quote and contain
+/// both kinds of quotes.
+fn get_guest_env(spec: &oci_spec::runtime::Spec) -> Vec<(String, String)> {
+    // these describe the container rather than configure the workload
+    const NAMES_TO_IGNORE: &[&str] = &[
+        "HOME",
+        "HOSTNAME",
+        "NOTIFY_SOCKET",
+        "PATH",
+        "TERM",
+        "container",
+    ];
+
+    lazy_static! {
+        static ref NAME_PATTERN: Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+    }
+
+    let mut env = vec![];
+
+    for var in spec.process().as_ref().unwrap().env().iter().flatten() {
+        let Some((name, value)) = var.split_once('=') else {
+            continue;
+        };
+
+        if NAMES_TO_IGNORE.contains(&name) || !NAME_PATTERN.is_match(name) {
+            continue;
+        }
+
+        // pam_env reads /etc/environment line by line, and strips a leading quote up to the next
+        // matching one without supporting escapes, so we can only preserve such a value by
+        // quoting it with the other kind of quote
+        let starts_with_quote = value.starts_with(['"', '\'']);
+        if value.contains(['\n', '\r'])
+            || starts_with_quote && value.contains('"') && value.contains('\'')
+        {
+            continue;
+        }
+
+        env.push((name.to_string(), value.to_string()));
+    }
+
+    env
+}
+
+/// Returns the entries of the engine-managed `/etc/hosts` file that should also be added to the
+/// guest's, reflecting options like podman-run's `--add-host`.
+///
+/// Loopback entries are left out, since the guest has its own.
+fn get_guest_hosts(spec: &oci_spec::runtime::Spec) -> Result<Vec<String>> {
+    let Some(source) = spec
+        .mounts()
+        .iter()
+        .flatten()
+        .find(|m| m.destination() == Path::new("/etc/hosts"))
+        .and_then(|m| m.source().as_ref())
+    else {
+        return Ok(vec![]);
+    };
+
+    let hosts = fs::read_to_string(source)
+        .with_context(|| format!("failed to read {}", source.display()))?;
+
+    let entries = hosts
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|entry| {
+            entry
+                .split_whitespace()
+                .next()
+                .and_then(|address| address.parse::<std::net::IpAddr>().ok())
+                .is_some_and(|address| !address.is_loopback())
+        })
+        .map(String::from)
+        .collect();
+
+    Ok(entries)
+}
+
 /// Returns the public key for the container.
 ///
 /// This first attempts to use the current user's key pair, just in case the VM does not support
@@ -779,8 +1587,14 @@ fn set_up_ssh_key_pair(
     Ok(fs::read_to_string(container_ssh_dir.join("id_rsa.pub"))?)
 }
 
-fn adjust_container_rlimits_and_resources(spec: &mut oci_spec::runtime::Spec) {
-    if let Some(process) = spec.process() {
+fn adjust_container_rlimits_and_resources(
+    spec: &mut oci_spec::runtime::Spec,
+    custom_options: &CustomOptions,
+) {
+    let forwards_all_ports =
+        custom_options.forward.is_empty() && custom_options.network != NetworkMode::None;
+
+    if let Some(process) = spec.process().as_ref().filter(|_| forwards_all_ports) {
         if let Some(rlimits) = process.rlimits() {
             let mut process = process.clone();
             let mut rlimits = rlimits.clone();
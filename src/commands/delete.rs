@@ -1,20 +1,21 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
 use std::ffi::OsStr;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
 
-use anyhow::{ensure, Result};
+use anyhow::Result;
 use camino::Utf8PathBuf;
-use serde::Deserialize;
 
-use crate::util::{crun, ensure_unmounted};
+use crate::util::{crun, ensure_unmounted, get_root_path};
 
-pub fn delete(args: &liboci_cli::Delete, raw_args: &[impl AsRef<OsStr>]) -> Result<()> {
+pub fn delete(
+    global: &liboci_cli::GlobalOpts,
+    args: &liboci_cli::Delete,
+    raw_args: &[impl AsRef<OsStr>],
+) -> Result<()> {
     // get container root path
 
     // the container might not exist because creation failed midway through, so we ignore errors
-    let root_path = get_root_path(&args.container_id).ok();
+    let root_path = get_root_path(global, &args.container_id).ok();
 
     // actually delete the container
 
@@ -42,22 +43,3 @@ pub fn delete(args: &liboci_cli::Delete, raw_args: &[impl AsRef<OsStr>]) -> Resu
 
     Ok(())
 }
-
-fn get_root_path(container_id: &str) -> Result<Utf8PathBuf> {
-    let output = Command::new("crun")
-        .arg("state")
-        .arg(container_id)
-        .stderr(Stdio::null())
-        .output()?;
-
-    ensure!(output.status.success());
-
-    #[derive(Deserialize)]
-    struct ContainerState {
-        rootfs: PathBuf,
-    }
-
-    let state: ContainerState = serde_json::from_slice(&output.stdout)?;
-
-    Ok(state.rootfs.try_into()?)
-}
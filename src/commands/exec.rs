@@ -2,15 +2,22 @@
 
 use std::env;
 use std::ffi::OsStr;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufReader, BufWriter};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, ensure, Result};
+use camino::Utf8Path;
 use clap::Parser;
+use lazy_static::lazy_static;
+use regex::Regex;
 
-use crate::util::{crun, fix_selinux_label};
+use crate::util::{crun, fix_selinux_label, get_root_path};
 
-pub fn exec(args: &liboci_cli::Exec, raw_args: &[impl AsRef<OsStr>]) -> Result<()> {
+pub fn exec(
+    global: &liboci_cli::GlobalOpts,
+    args: &liboci_cli::Exec,
+    raw_args: &[impl AsRef<OsStr>],
+) -> Result<()> {
     assert!(args.command.is_empty());
 
     // load exec process config
@@ -20,8 +27,27 @@ pub fn exec(args: &liboci_cli::Exec, raw_args: &[impl AsRef<OsStr>]) -> Result<(
         serde_json::from_reader(File::open(process_config_path).map(BufReader::new)?)?;
 
     let command = process.args().as_ref().expect("command specified");
+    let exec_args = parse_args(command)?;
+
+    let new_command = if exec_args.container {
+        build_command_from_args(exec_args, &GuestContext::default())?
+    } else {
+        let root_path = get_root_path(global, &args.container_id)?;
+        let container_config_path = root_path.join("crun-vm/config.json");
+        let container_spec = oci_spec::runtime::Spec::load(container_config_path)?;
+        let container_process = container_spec.process().as_ref().expect("process config");
+
+        let context = GuestContext::new(&process, container_process, &root_path)?;
+
+        // The command will actually run in the container as the container's user and in its root,
+        // and is then responsible for reproducing the requested user, environment, and working
+        // directory in the guest.
+        process.set_user(container_process.user().clone());
+        process.set_cwd("/".into());
+
+        build_command_from_args(exec_args, &context)?
+    };
 
-    let new_command = build_command(command)?;
     process.set_args(Some(new_command));
 
     fix_selinux_label(&mut process);
@@ -40,15 +66,28 @@ pub fn exec(args: &liboci_cli::Exec, raw_args: &[impl AsRef<OsStr>]) -> Result<(
     Ok(())
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+enum ExecBackend {
+    /// Use ssh, falling back to the guest agent if the guest has no reachable ssh server.
+    Auto,
+    /// Use ssh.
+    Ssh,
+    /// Use the QEMU guest agent.
+    Agent,
+}
+
 #[derive(Parser, Debug)]
 #[clap(no_binary_name = true, disable_help_flag = true)]
 struct ExecArgs {
-    #[clap(long = "as", default_value = "root")]
-    user: String,
+    #[clap(long = "as")]
+    user: Option<String>,
 
-    #[clap(long, conflicts_with = "user")]
+    #[clap(long, conflicts_with_all = ["user", "backend"])]
     container: bool,
 
+    #[clap(long, value_enum, default_value_t = ExecBackend::Auto)]
+    backend: ExecBackend,
+
     #[clap(long = "timeout")]
     timeout: Option<u32>,
 
@@ -56,7 +95,115 @@ struct ExecArgs {
     command: Vec<String>,
 }
 
-fn build_command(original_command: &Vec<String>) -> Result<Vec<String>> {
+/// The user, environment, and working directory that the engine requested for an exec'd process,
+/// to be reproduced in the guest.
+#[derive(Default)]
+struct GuestContext {
+    user: Option<String>,
+    env: Vec<(String, String)>,
+    cwd: Option<String>,
+}
+
+impl GuestContext {
+    fn new(
+        exec_process: &oci_spec::runtime::Process,
+        container_process: &oci_spec::runtime::Process,
+        root_path: &Utf8Path,
+    ) -> Result<GuestContext> {
+        // The exec process inherits the container's environment, working directory, and user, so
+        // we only reproduce what differs from those, i.e., what was given to the engine's exec
+        // command. TERM is set up by ssh itself.
+
+        lazy_static! {
+            static ref NAME_PATTERN: Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+        }
+
+        let container_env = container_process.env().clone().unwrap_or_default();
+        let mut env = vec![];
+
+        for var in exec_process.env().iter().flatten() {
+            if container_env.contains(var) {
+                continue;
+            }
+
+            let (name, value) = var
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid environment variable '{var}'"))?;
+
+            ensure!(
+                NAME_PATTERN.is_match(name),
+                "invalid environment variable name '{name}'"
+            );
+
+            if name != "TERM" {
+                env.push((name.to_string(), value.to_string()));
+            }
+        }
+
+        // Our own container's working directory is not the one the engine knows about, so we
+        // compare against the one saved by create. We also can't tell whether "/" was requested
+        // explicitly or is just the image's default. We assume the latter, letting the guest
+        // user's login directory be used instead.
+        let original_cwd = fs::read_to_string(root_path.join("crun-vm/original-cwd")).ok();
+        let cwd = exec_process.cwd().to_str().map(str::to_string);
+        let cwd = cwd.filter(|cwd| cwd != "/" && Some(cwd) != original_cwd.as_ref());
+
+        let user = if exec_process.user().uid() == container_process.user().uid() {
+            None
+        } else {
+            // The engine resolves user names using the container image's /etc/passwd, which for
+            // bootable containers is also the guest's. Otherwise, there is no way to know the name
+            // of the user in the guest.
+            let uid = exec_process.user().uid();
+            let original_root_path = root_path.parent().and_then(Utf8Path::parent).unwrap();
+
+            let name = fs::read_to_string(original_root_path.join("etc/passwd"))
+                .ok()
+                .and_then(|passwd| find_user_name(&passwd, uid));
+
+            match name {
+                Some(name) => Some(name),
+                None => bail!(
+                    "can't map user ID {uid} to a user name in the VM; use --as <user_name> instead"
+                ),
+            }
+        };
+
+        Ok(GuestContext { user, env, cwd })
+    }
+
+    /// Shell code that sets up the environment and working directory, to be prepended to the
+    /// command.
+    fn shell_prefix(&self) -> String {
+        let mut prefix = String::new();
+
+        if let Some(cwd) = &self.cwd {
+            prefix.push_str(&format!("cd {} || exit; ", shell_quote(cwd)));
+        }
+
+        for (name, value) in &self.env {
+            prefix.push_str(&format!("export {name}={}; ", shell_quote(value)));
+        }
+
+        prefix
+    }
+}
+
+fn find_user_name(passwd: &str, uid: u32) -> Option<String> {
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.as_slice() {
+            [name, _, line_uid, ..] if line_uid.parse() == Ok(uid) => Some(name.to_string()),
+            _ => None,
+        }
+    })
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn parse_args(original_command: &[String]) -> Result<ExecArgs> {
     let cmd = if original_command.first() == Some(&"--".to_string()) {
         // Podman gobbles up a -- before the command in some cases, but not in others. Gobble it up
         // ourselves to avoid user confusion; see https://github.com/containers/crun-vm/issues/117.
@@ -67,6 +214,18 @@ fn build_command(original_command: &Vec<String>) -> Result<Vec<String>> {
 
     let mut args: ExecArgs = ExecArgs::parse_from(cmd);
 
+    if args.command.starts_with(&["".to_string()]) {
+        args.command.remove(0);
+    }
+
+    Ok(args)
+}
+
+pub fn build_command(original_command: &[String]) -> Result<Vec<String>> {
+    build_command_from_args(parse_args(original_command)?, &GuestContext::default())
+}
+
+fn build_command_from_args(args: ExecArgs, context: &GuestContext) -> Result<Vec<String>> {
     let timeout = if let Some(t) = args.timeout {
         t
     } else if let Some(t) = env::var_os("CRUN_VM_EXEC_TIMEOUT") {
@@ -78,9 +237,18 @@ fn build_command(original_command: &Vec<String>) -> Result<Vec<String>> {
         0
     };
 
-    if args.command.starts_with(&["".to_string()]) {
-        args.command.remove(0);
-    }
+    let user = match (args.user, &context.user) {
+        (Some(user), Some(engine_user)) => {
+            ensure!(
+                &user == engine_user,
+                "--as {user} conflicts with the user given to the container engine ({engine_user})"
+            );
+            user
+        }
+        (Some(user), None) => user,
+        (None, Some(engine_user)) => engine_user.clone(),
+        (None, None) => "root".to_string(),
+    };
 
     let command = if args.container {
         if args.command.is_empty() {
@@ -89,14 +257,34 @@ fn build_command(original_command: &Vec<String>) -> Result<Vec<String>> {
             args.command
         }
     } else {
-        [
-            "/crun-vm/exec.sh".to_string(),
-            timeout.to_string(),
-            args.user,
+        let subcommand = match args.backend {
+            ExecBackend::Agent => "agent-exec",
+            ExecBackend::Ssh | ExecBackend::Auto => "ssh-exec",
+        };
+
+        let mut command: Vec<String> = [
+            "/crun-vm/crun-vm",
+            "in-container",
+            subcommand,
+            "--timeout",
+            &timeout.to_string(),
+            "--as",
+            &user,
+            "--prefix",
+            &context.shell_prefix(),
         ]
         .into_iter()
-        .chain(args.command)
-        .collect()
+        .map(String::from)
+        .collect();
+
+        if args.backend == ExecBackend::Auto {
+            command.push("--fallback-to-agent".to_string());
+        }
+
+        command.push("--".to_string());
+        command.extend(args.command);
+
+        command
     };
 
     Ok(command)
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::io::{self, IsTerminal, Read, Write};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::Deserialize;
+
+use crate::commands::in_container::{fail, virsh, GuestCommandArgs, EXIT_GUEST_NOT_BOOTED};
+
+pub fn agent_exec(args: &GuestCommandArgs) -> Result<()> {
+    if args.command.is_empty() {
+        bail!("the guest agent exec backend does not support interactive sessions");
+    }
+
+    wait_for_agent(args.timeout);
+
+    // The guest agent only passes input to the command once it is launched, and only returns the
+    // command's output once it terminates, so stdin must be read in full up front. If stdin is a
+    // terminal, there is no input to pass.
+
+    let mut input = Vec::new();
+    if !io::stdin().is_terminal() {
+        io::stdin().read_to_end(&mut input)?;
+    }
+
+    // Run the command through the user's login shell, as ssh would.
+
+    let command = serde_json::json!({
+        "execute": "guest-exec",
+        "arguments": {
+            "path": "su",
+            "arg": [
+                "--login",
+                &args.user,
+                "--command",
+                format!("{}{}", args.prefix, args.command.join(" ")),
+            ],
+            "input-data": BASE64_STANDARD.encode(&input),
+            "capture-output": true,
+        }
+    });
+
+    #[derive(Deserialize)]
+    struct ExecReturn {
+        pid: i64,
+    }
+
+    let pid = agent_command::<ExecReturn>(&command)
+        .context("failed to launch command through the guest agent")?
+        .pid;
+
+    // poll until the command terminates
+
+    #[derive(Deserialize)]
+    struct ExecStatusReturn {
+        exited: bool,
+        exitcode: Option<i32>,
+        signal: Option<i32>,
+        #[serde(rename = "out-data")]
+        out_data: Option<String>,
+        #[serde(rename = "err-data")]
+        err_data: Option<String>,
+    }
+
+    let command = serde_json::json!({
+        "execute": "guest-exec-status",
+        "arguments": { "pid": pid }
+    });
+
+    let status = loop {
+        let status: ExecStatusReturn = agent_command(&command)?;
+        if status.exited {
+            break status;
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    if let Some(data) = &status.out_data {
+        io::stdout().write_all(&BASE64_STANDARD.decode(data)?)?;
+        io::stdout().flush()?;
+    }
+
+    if let Some(data) = &status.err_data {
+        io::stderr().write_all(&BASE64_STANDARD.decode(data)?)?;
+        io::stderr().flush()?;
+    }
+
+    let exit_code = match (status.exitcode, status.signal) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 255,
+    };
+
+    process::exit(exit_code);
+}
+
+fn wait_for_agent(timeout: u32) {
+    let end_time = Instant::now() + Duration::from_secs(timeout.into());
+
+    while !agent_is_responsive() {
+        if timeout > 0 && Instant::now() >= end_time {
+            fail(
+                EXIT_GUEST_NOT_BOOTED,
+                &format!("timed out after {timeout}s waiting for the VM's guest agent"),
+            );
+        }
+
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+pub fn agent_is_responsive() -> bool {
+    let ping = serde_json::json!({ "execute": "guest-ping" });
+    agent_command::<serde_json::Value>(&ping).is_ok()
+}
+
+pub fn agent_command<T: for<'de> Deserialize<'de>>(command: &serde_json::Value) -> Result<T> {
+    #[derive(Deserialize)]
+    struct Response<T> {
+        #[serde(rename = "return")]
+        ret: T,
+    }
+
+    let output = virsh(&["qemu-agent-command", "domain", &command.to_string()])?;
+
+    let response: Response<T> = serde_json::from_str(&output)
+        .map_err(|e| anyhow!("unexpected guest agent response: {e}"))?;
+
+    Ok(response.ret)
+}
@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use serde::Deserialize;
+
+use crate::commands::in_container::agent_exec::agent_command;
+
+#[derive(clap::Args, Debug)]
+pub struct ReportGuestAddressesArgs {
+    /// The file to keep up to date with the guest's addresses.
+    path: Utf8PathBuf,
+}
+
+#[derive(Deserialize)]
+struct Interface {
+    name: String,
+    #[serde(rename = "hardware-address", default)]
+    hardware_address: String,
+    #[serde(rename = "ip-addresses", default)]
+    ip_addresses: Vec<IpAddress>,
+}
+
+#[derive(Deserialize)]
+struct IpAddress {
+    #[serde(rename = "ip-address")]
+    ip_address: String,
+    prefix: u8,
+}
+
+/// Periodically ask the guest agent for the guest's IP addresses, and write them to a file with
+/// one "<interface> <mac> <address>/<prefix>" line per address.
+///
+/// Runs until killed. The file is only created once the guest agent responds.
+pub fn report_guest_addresses(args: &ReportGuestAddressesArgs) -> Result<()> {
+    let mut last_report = None;
+
+    loop {
+        let command = serde_json::json!({ "execute": "guest-network-get-interfaces" });
+
+        if let Ok(interfaces) = agent_command::<Vec<Interface>>(&command) {
+            let report: String = interfaces
+                .iter()
+                .filter(|i| i.name != "lo")
+                .flat_map(|i| {
+                    i.ip_addresses.iter().map(move |a| {
+                        format!(
+                            "{} {} {}/{}\n",
+                            i.name, i.hardware_address, a.ip_address, a.prefix
+                        )
+                    })
+                })
+                .collect();
+
+            if last_report.as_ref() != Some(&report) {
+                // replace the file atomically so that readers never see partial contents
+                let tmp_path = args.path.with_extension("tmp");
+                fs::write(&tmp_path, &report)?;
+                fs::rename(&tmp_path, &args.path)?;
+                last_report = Some(report);
+            }
+        }
+
+        thread::sleep(Duration::from_secs(5));
+    }
+}
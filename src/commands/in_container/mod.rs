@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Helpers that crun-vm runs *inside* the container, where its own binary is bind mounted at
+// /crun-vm/crun-vm. These are not meant to be invoked by users.
+
+mod agent_exec;
+mod guest_addresses;
+pub mod network;
+mod notify_ready;
+mod ssh_exec;
+mod vsock_proxy;
+
+use std::process::{self, Command};
+
+use anyhow::{ensure, Result};
+
+#[derive(clap::Subcommand, Debug)]
+pub enum InContainerCmd {
+    /// Run a command in the guest through the QEMU guest agent.
+    AgentExec(GuestCommandArgs),
+
+    /// Run a command in the guest over ssh, waiting for the guest to become reachable.
+    SshExec(ssh_exec::SshExecArgs),
+
+    /// Relay stdin and stdout to and from a vsock port in the guest.
+    VsockProxy(vsock_proxy::VsockProxyArgs),
+
+    /// Adjust the libvirt domain XML to the container's network interfaces.
+    SetUpNetworkInterfaces(network::SetUpNetworkInterfacesArgs),
+
+    /// Check that the ports to forward to the guest are not in use.
+    CheckForwardedPorts(network::CheckForwardedPortsArgs),
+
+    /// Keep a file up to date with the guest's IP addresses, as reported by the guest agent.
+    ReportGuestAddresses(guest_addresses::ReportGuestAddressesArgs),
+
+    /// Notify systemd once the guest reports that it has finished booting.
+    NotifyReady(notify_ready::NotifyReadyArgs),
+}
+
+pub fn in_container(cmd: &InContainerCmd) -> Result<()> {
+    match cmd {
+        InContainerCmd::AgentExec(args) => agent_exec::agent_exec(args),
+        InContainerCmd::SshExec(args) => ssh_exec::ssh_exec(args),
+        InContainerCmd::VsockProxy(args) => vsock_proxy::vsock_proxy(args),
+        InContainerCmd::SetUpNetworkInterfaces(args) => network::set_up_network_interfaces(args),
+        InContainerCmd::CheckForwardedPorts(args) => network::check_forwarded_ports(args),
+        InContainerCmd::ReportGuestAddresses(args) => guest_addresses::report_guest_addresses(args),
+        InContainerCmd::NotifyReady(args) => notify_ready::notify_ready(args),
+    }
+}
+
+/// A command to run in the guest, as set up by `commands::exec::build_command`.
+#[derive(clap::Args, Debug)]
+pub struct GuestCommandArgs {
+    /// Give up if the guest can't be reached within this many seconds; 0 means never.
+    #[clap(long)]
+    timeout: u32,
+
+    #[clap(long = "as")]
+    user: String,
+
+    /// Shell code to run before the command.
+    #[clap(long, default_value = "")]
+    prefix: String,
+
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+// Exit statuses for failing to reach the guest, so that scripts can tell those failures apart from
+// the command itself failing. These are distinct from ssh's own 255 and from the statuses that
+// shells and container engines commonly use.
+const EXIT_GUEST_NOT_BOOTED: i32 = 251;
+const EXIT_SSHD_REFUSED: i32 = 252;
+const EXIT_AUTH_DENIED: i32 = 253;
+
+/// Print an error message and exit with the given status.
+fn fail(exit_code: i32, message: &str) -> ! {
+    eprintln!("crun-vm: {message}");
+    process::exit(exit_code);
+}
+
+/// Run `virsh` against the libvirt daemon started by the container's entrypoint.
+///
+/// Returns the command's standard output.
+fn virsh(args: &[&str]) -> Result<String> {
+    let output = Command::new("/crun-vm/virsh").args(args).output()?;
+
+    ensure!(
+        output.status.success(),
+        "virsh failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    Ok(String::from_utf8(output.stdout)?)
+}
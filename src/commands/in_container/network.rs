@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Write};
+use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use camino::Utf8PathBuf;
+use nix::ifaddrs::getifaddrs;
+
+/// Alias of the network interface given to the guest when using `--network passt`.
+pub const DEFAULT_INTERFACE_ALIAS: &str = "ua-crun-vm-default";
+
+/// Alias of the network interface that stands for one interface per container network interface
+/// when using `--network passt-all`.
+pub const ALL_INTERFACES_ALIAS: &str = "ua-crun-vm-all";
+
+#[derive(clap::Args, Debug)]
+pub struct SetUpNetworkInterfacesArgs {
+    domain_xml: Utf8PathBuf,
+}
+
+/// Adjust the guest's network interfaces to the network interfaces the container actually has.
+///
+/// This can't be done at create time, since engines may only configure the container's network
+/// namespace after that.
+pub fn set_up_network_interfaces(args: &SetUpNetworkInterfacesArgs) -> Result<()> {
+    let container_interfaces: BTreeSet<String> = getifaddrs()
+        .context("failed to list network interfaces")?
+        .map(|ifaddr| ifaddr.interface_name)
+        .filter(|name| name != "lo")
+        .collect();
+
+    let mut domain = minidom::Element::from_reader_with_prefixes(
+        BufReader::new(File::open(&args.domain_xml)?),
+        "".to_string(),
+    )?;
+
+    let Some(devices) = domain.get_child_mut("devices", "") else {
+        return Ok(());
+    };
+
+    let mut new_interfaces = vec![];
+
+    while let Some(interface) = devices.remove_child("interface", "") {
+        let alias = interface
+            .get_child("alias", "")
+            .and_then(|a| a.attr("name"))
+            .unwrap_or("")
+            .to_string();
+
+        if container_interfaces.is_empty()
+            && (alias == DEFAULT_INTERFACE_ALIAS || alias == ALL_INTERFACES_ALIAS)
+        {
+            // e.g., the engine was given --network none, so passt would have nothing to do
+            continue;
+        }
+
+        if alias != ALL_INTERFACES_ALIAS {
+            new_interfaces.push(interface);
+            continue;
+        }
+
+        for (i, name) in container_interfaces.iter().enumerate() {
+            let mut new_interface = minidom::Element::builder("interface", "").build();
+
+            for (attr_name, value) in interface.attrs() {
+                new_interface.set_attr(attr_name, value);
+            }
+
+            new_interface.append_child(
+                minidom::Element::builder("source", "")
+                    .attr("dev", name.as_str())
+                    .build(),
+            );
+
+            for child in interface.children().filter(|c| c.name() != "alias") {
+                let mut child = child.clone();
+                if child.name() == "portForward" {
+                    // have each passt instance only bind ports on its own interface
+                    child.set_attr("dev", name.as_str());
+                } else if child.name() == "mac" {
+                    // give each interface a different but still deterministic MAC address
+                    if let Some(mac) = child.attr("address").map(|a| offset_mac_address(a, i)) {
+                        child.set_attr("address", mac?);
+                    }
+                }
+                new_interface.append_child(child);
+            }
+
+            new_interfaces.push(new_interface);
+        }
+    }
+
+    for interface in new_interfaces {
+        devices.append_child(interface);
+    }
+
+    let mut writer = File::create(&args.domain_xml)?;
+    domain.write_to_decl(&mut writer)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn offset_mac_address(mac: &str, offset: usize) -> Result<String> {
+    let (prefix, last_octet) = mac
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("invalid MAC address '{mac}'"))?;
+
+    let last_octet = u8::from_str_radix(last_octet, 16)?.wrapping_add(offset as u8);
+
+    Ok(format!("{prefix}:{last_octet:02x}"))
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CheckForwardedPortsArgs {
+    domain_xml: Utf8PathBuf,
+}
+
+/// Fail with a clear error if any port that the guest's network interfaces forward is already in
+/// use in the container's network namespace, e.g., by another container in the same pod, instead
+/// of letting passt fail obscurely.
+///
+/// Interfaces that forward all ports are not checked, as passt then simply skips ports in use.
+pub fn check_forwarded_ports(args: &CheckForwardedPortsArgs) -> Result<()> {
+    let domain = minidom::Element::from_reader_with_prefixes(
+        BufReader::new(File::open(&args.domain_xml)?),
+        "".to_string(),
+    )?;
+
+    let port_forwards = domain
+        .get_child("devices", "")
+        .into_iter()
+        .flat_map(|devices| devices.children().filter(|c| c.is("interface", "")))
+        .flat_map(|interface| interface.children().filter(|c| c.is("portForward", "")));
+
+    for port_forward in port_forwards {
+        let protocol = port_forward.attr("proto").unwrap_or("tcp");
+
+        let ranges = port_forward
+            .children()
+            .filter(|c| c.is("range", "") && c.attr("exclude") != Some("yes"));
+
+        for range in ranges {
+            let start: u16 = range.attr("start").unwrap_or_default().parse()?;
+            let end: u16 = range.attr("end").map_or(Ok(start), str::parse)?;
+
+            for port in start..=end {
+                let result = match protocol {
+                    "udp" => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).map(drop),
+                    _ => TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).map(drop),
+                };
+
+                if let Err(e) = result {
+                    ensure!(
+                        e.kind() != ErrorKind::AddrInUse,
+                        "port {port}/{protocol} can't be forwarded to the VM because it is already in use in the container's network namespace, possibly by another container in the same pod; use --forward to forward a different set of ports"
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
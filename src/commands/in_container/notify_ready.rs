@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::env;
+use std::io::{BufRead, BufReader};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixStream};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8PathBuf;
+
+#[derive(clap::Args, Debug)]
+pub struct NotifyReadyArgs {
+    /// The socket backing the virtio-serial port that the guest reports readiness through.
+    socket: Utf8PathBuf,
+}
+
+/// Wait for the guest to report that it has finished booting, then notify systemd through the
+/// socket in `NOTIFY_SOCKET`.
+pub fn notify_ready(args: &NotifyReadyArgs) -> Result<()> {
+    let notify_socket =
+        env::var("NOTIFY_SOCKET").map_err(|_| anyhow!("NOTIFY_SOCKET is not set"))?;
+
+    // QEMU only creates the socket once the VM starts
+    let stream = loop {
+        match UnixStream::connect(&args.socket) {
+            Ok(stream) => break stream,
+            Err(_) => thread::sleep(Duration::from_millis(500)),
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        if line?.trim() == "READY" {
+            let addr = match notify_socket.strip_prefix('@') {
+                Some(name) => SocketAddr::from_abstract_name(name)?,
+                None => SocketAddr::from_pathname(&notify_socket)?,
+            };
+
+            UnixDatagram::unbound()?
+                .send_to_addr(b"READY=1", &addr)
+                .context("failed to notify systemd")?;
+
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,338 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::commands::in_container::agent_exec::{agent_exec, agent_is_responsive};
+use crate::commands::in_container::{
+    fail, GuestCommandArgs, EXIT_AUTH_DENIED, EXIT_GUEST_NOT_BOOTED, EXIT_SSHD_REFUSED,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct SshExecArgs {
+    #[clap(flatten)]
+    command: GuestCommandArgs,
+
+    /// Use the guest agent instead if the guest is running it but doesn't accept ssh connections.
+    ///
+    /// This only happens for non-interactive commands, once ssh has kept failing for a while.
+    #[clap(long)]
+    fallback_to_agent: bool,
+}
+
+/// Holds the transport over which sshd was last reached, so that later execs can skip probing.
+const SSH_SUCCESSFUL_PATH: &str = "/crun-vm/ssh-successful";
+const VSOCK_CID_PATH: &str = "/crun-vm/vsock-cid";
+
+/// When falling back to the guest agent, for how long sshd must keep refusing or closing
+/// connections while the agent is responsive, since sshd may simply not have started yet.
+const AGENT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// How to reach the guest's sshd.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Transport {
+    /// Over vsock, which works regardless of the guest's network configuration.
+    Vsock,
+    /// Through the guest's network.
+    Tcp,
+}
+
+impl Transport {
+    fn name(self) -> &'static str {
+        match self {
+            Transport::Vsock => "vsock",
+            Transport::Tcp => "tcp",
+        }
+    }
+}
+
+/// Why an attempt to reach the guest's sshd failed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Failure {
+    /// The guest isn't reachable at all, most likely because it is still booting.
+    GuestNotBooted,
+    /// The guest is reachable but nothing is accepting ssh connections, most likely because sshd
+    /// hasn't started yet.
+    SshdRefused,
+    /// The guest accepted the connection but closed it right away, which may mean that it is still
+    /// booting or that no ssh server is listening.
+    ConnectionClosed,
+    /// sshd rejected our key.
+    AuthDenied,
+    /// The attempt was cut short because the timeout expired.
+    TimedOut,
+}
+
+pub fn ssh_exec(args: &SshExecArgs) -> Result<()> {
+    let transport = match remembered_transport()? {
+        Some(transport) => transport,
+        None => match wait_for_sshd(args)? {
+            Some(transport) => transport,
+            None => return agent_exec(&args.command),
+        },
+    };
+
+    // replace this process with the actual ssh session
+
+    let user_args = &args.command;
+
+    let mut ssh = if user_args.prefix.is_empty() {
+        let mut ssh = ssh_command(transport, &user_args.user, &["-o", "LogLevel=ERROR"])?;
+        ssh.arg("--").args(&user_args.command);
+        ssh
+    } else if !user_args.command.is_empty() {
+        let mut ssh = ssh_command(transport, &user_args.user, &["-o", "LogLevel=ERROR"])?;
+        let command = format!("{}{}", user_args.prefix, user_args.command.join(" "));
+        ssh.arg("--").arg(command);
+        ssh
+    } else {
+        // login shell, which ssh only gives a tty by itself if no command is specified
+        let options: &[&str] = if io::stdin().is_terminal() {
+            &["-o", "LogLevel=ERROR", "-t"]
+        } else {
+            &["-o", "LogLevel=ERROR"]
+        };
+        let mut ssh = ssh_command(transport, &user_args.user, options)?;
+        ssh.arg("--")
+            .arg(format!("{}exec \"$SHELL\" -l", user_args.prefix));
+        ssh
+    };
+
+    Err(ssh.exec()).context("failed to run ssh")
+}
+
+/// Retry connecting to the guest's sshd until it works or the timeout expires.
+///
+/// Returns the transport to use, or `None` if the guest agent should be used instead.
+fn wait_for_sshd(args: &SshExecArgs) -> Result<Option<Transport>> {
+    let timeout = args.command.timeout;
+    let start_time = Instant::now();
+    let deadline = (timeout > 0).then(|| start_time + Duration::from_secs(timeout.into()));
+
+    // the guest agent can't run interactive sessions, so don't fall back to it for those
+    let may_fall_back =
+        args.fallback_to_agent && !args.command.command.is_empty() && !io::stdin().is_terminal();
+
+    let mut last_failure = Failure::GuestNotBooted;
+    let mut backoff = MIN_BACKOFF;
+
+    // since when sshd has kept refusing or closing connections while the agent is responsive
+    let mut refused_with_agent_since: Option<Instant> = None;
+
+    loop {
+        // if any transport reached sshd, that's the more informative failure to report
+        let mut round_failure = Failure::GuestNotBooted;
+
+        // the entrypoint only writes the CID once the VM is started
+        let transports: &[Transport] = if Path::new(VSOCK_CID_PATH).try_exists()? {
+            &[Transport::Vsock, Transport::Tcp]
+        } else {
+            &[Transport::Tcp]
+        };
+
+        for &transport in transports {
+            match try_ssh(transport, &args.command.user, deadline)? {
+                Ok(()) => {
+                    remember_transport(transport)?;
+                    return Ok(Some(transport));
+                }
+                Err(Failure::AuthDenied) if io::stdin().is_terminal() => {
+                    // sshd is up, and the actual session may still authenticate interactively,
+                    // e.g., with a password
+                    remember_transport(transport)?;
+                    return Ok(Some(transport));
+                }
+                Err(Failure::AuthDenied) => fail(
+                    EXIT_AUTH_DENIED,
+                    &format!(
+                        "the VM's ssh server denied access to user '{}'",
+                        args.command.user
+                    ),
+                ),
+                Err(Failure::SshdRefused) => round_failure = Failure::SshdRefused,
+                Err(Failure::ConnectionClosed) if round_failure != Failure::SshdRefused => {
+                    round_failure = Failure::ConnectionClosed
+                }
+                Err(Failure::ConnectionClosed | Failure::GuestNotBooted) => {}
+                Err(Failure::TimedOut) => round_failure = last_failure,
+            }
+        }
+
+        last_failure = round_failure;
+
+        let refused_or_closed = matches!(
+            last_failure,
+            Failure::SshdRefused | Failure::ConnectionClosed
+        );
+
+        if may_fall_back && refused_or_closed && agent_is_responsive() {
+            let since = *refused_with_agent_since.get_or_insert_with(Instant::now);
+            let timed_out = deadline.is_some_and(|d| Instant::now() >= d);
+            if since.elapsed() >= AGENT_GRACE_PERIOD || timed_out {
+                // the guest is up but not accepting ssh connections
+                return Ok(None);
+            }
+        } else {
+            refused_with_agent_since = None;
+        }
+
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                match last_failure {
+                    Failure::SshdRefused => fail(
+                        EXIT_SSHD_REFUSED,
+                        &format!(
+                            "timed out after {timeout}s: the VM is up but its ssh server is refusing connections"
+                        ),
+                    ),
+                    _ => fail(
+                        EXIT_GUEST_NOT_BOOTED,
+                        &format!("timed out after {timeout}s: the VM never became reachable"),
+                    ),
+                }
+            }
+            thread::sleep(backoff.min(deadline - now));
+        } else {
+            thread::sleep(backoff);
+        }
+
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Avoid waiting for sshd next time.
+fn remember_transport(transport: Transport) -> Result<()> {
+    fs::write(SSH_SUCCESSFUL_PATH, transport.name())?;
+    Ok(())
+}
+
+/// Returns the transport over which sshd was last reached, if any and if it is still the one to
+/// use.
+fn remembered_transport() -> Result<Option<Transport>> {
+    let transport = match fs::read_to_string(SSH_SUCCESSFUL_PATH) {
+        Ok(name) if name.trim() == Transport::Vsock.name() => return Ok(Some(Transport::Vsock)),
+        Ok(_) => Transport::Tcp,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    // sshd may have been reached over tcp only because the vsock CID wasn't known yet, in which
+    // case give vsock a chance now that it is
+    let cid_modified = match fs::metadata(VSOCK_CID_PATH) {
+        Ok(metadata) => metadata.modified()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Some(transport)),
+        Err(e) => return Err(e.into()),
+    };
+
+    if cid_modified > fs::metadata(SSH_SUCCESSFUL_PATH)?.modified()? {
+        Ok(None)
+    } else {
+        Ok(Some(transport))
+    }
+}
+
+/// Attempt a non-interactive ssh connection, giving up at `deadline`.
+///
+/// Fails with an error if the attempt failed in an unexpected way.
+fn try_ssh(
+    transport: Transport,
+    user: &str,
+    deadline: Option<Instant>,
+) -> Result<Result<(), Failure>> {
+    let mut child = ssh_command(transport, user, &["-o", "BatchMode=yes"])?
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to run ssh")?;
+
+    let status: ExitStatus = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            child.kill()?;
+            child.wait()?;
+            return Ok(Err(Failure::TimedOut));
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let mut stderr = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut stderr)?;
+
+    if status.code() != Some(255) {
+        // ssh connected and ran a login shell, which exited immediately because stdin is empty
+        return Ok(Ok(()));
+    }
+
+    let contains = |pattern: &str| stderr.to_lowercase().contains(&pattern.to_lowercase());
+
+    if contains("System is booting up") {
+        // pam_nologin rejects non-root logins while the guest boots, which also makes ssh report
+        // "Permission denied", so this must be checked first
+        Ok(Err(Failure::GuestNotBooted))
+    } else if contains("Permission denied") {
+        Ok(Err(Failure::AuthDenied))
+    } else if contains("Connection refused") {
+        Ok(Err(Failure::SshdRefused))
+    } else if [
+        "Connection closed by remote host",
+        "Connection reset by peer",
+    ]
+    .iter()
+    .any(|p| contains(p))
+    {
+        Ok(Err(Failure::ConnectionClosed))
+    } else if [
+        "Connection timed out",
+        "No route to host",
+        "failed to connect to vsock port",
+    ]
+    .iter()
+    .any(|p| contains(p))
+    {
+        Ok(Err(Failure::GuestNotBooted))
+    } else {
+        // unexpected failure, report it as ssh would
+        eprint!("{stderr}");
+        process::exit(255);
+    }
+}
+
+fn ssh_command(transport: Transport, user: &str, options: &[&str]) -> Result<Command> {
+    let mut command = Command::new("ssh");
+
+    command
+        .args(["-o", "StrictHostKeyChecking=no"])
+        .args(["-o", "UserKnownHostsFile=/dev/null"])
+        .args(["-o", "ConnectTimeout=10"]);
+
+    if transport == Transport::Vsock {
+        let cid = fs::read_to_string(VSOCK_CID_PATH)?;
+        command.args([
+            "-o",
+            &format!(
+                "ProxyCommand=/crun-vm/crun-vm in-container vsock-proxy {} 22",
+                cid.trim()
+            ),
+        ]);
+    }
+
+    command.args(options).args(["-l", user, "localhost"]);
+
+    Ok(command)
+}
@@ -0,0 +1,47 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+use anyhow::{Context, Result};
+use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, VsockAddr};
+
+#[derive(clap::Args, Debug)]
+pub struct VsockProxyArgs {
+    cid: u32,
+    port: u32,
+}
+
+/// Relay stdin and stdout to and from a vsock port in the guest, for use as an ssh ProxyCommand.
+pub fn vsock_proxy(args: &VsockProxyArgs) -> Result<()> {
+    let fd = socket(
+        AddressFamily::Vsock,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+
+    connect(fd.as_raw_fd(), &VsockAddr::new(args.cid, args.port))
+        .with_context(|| format!("failed to connect to vsock port {}:{}", args.cid, args.port))?;
+
+    // vsock stream sockets behave like unix stream sockets as far as reading, writing, and
+    // shutting down go, so we reuse the latter's convenient API
+    let stream = UnixStream::from(fd);
+    let mut stream_write = stream.try_clone()?;
+
+    thread::spawn(move || {
+        let _ = io::copy(&mut io::stdin().lock(), &mut stream_write);
+        let _ = stream_write.shutdown(Shutdown::Write);
+    });
+
+    // write to stdout unbuffered, since the protocol being relayed isn't line-based
+    let mut stdout = File::from(io::stdout().as_fd().try_clone_to_owned()?);
+    io::copy(&mut &stream, &mut stdout)?;
+    stdout.flush()?;
+
+    Ok(())
+}
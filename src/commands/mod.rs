@@ -3,3 +3,4 @@
 pub mod create;
 pub mod delete;
 pub mod exec;
+pub mod in_container;
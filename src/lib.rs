@@ -28,6 +28,9 @@ enum Command {
 
     #[clap(flatten)]
     Common(Box<liboci_cli::CommonCmd>),
+
+    #[clap(subcommand, hide = true)]
+    InContainer(commands::in_container::InContainerCmd),
 }
 
 pub fn main(args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<()> {
@@ -42,7 +45,9 @@ pub fn main(args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<()> {
         Command::Standard(cmd) => {
             match *cmd {
                 liboci_cli::StandardCmd::Create(args) => commands::create::create(&args, &raw_args),
-                liboci_cli::StandardCmd::Delete(args) => commands::delete::delete(&args, &raw_args),
+                liboci_cli::StandardCmd::Delete(args) => {
+                    commands::delete::delete(&parsed_args.global, &args, &raw_args)
+                }
                 liboci_cli::StandardCmd::Start(_)
                 | liboci_cli::StandardCmd::State(_)
                 | liboci_cli::StandardCmd::Kill(_) => {
@@ -53,7 +58,9 @@ pub fn main(args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<()> {
         }
         Command::Common(cmd) => {
             match *cmd {
-                liboci_cli::CommonCmd::Exec(args) => commands::exec::exec(&args, &raw_args),
+                liboci_cli::CommonCmd::Exec(args) => {
+                    commands::exec::exec(&parsed_args.global, &args, &raw_args)
+                }
                 liboci_cli::CommonCmd::Checkpointt(_)
                 | liboci_cli::CommonCmd::Events(_)
                 | liboci_cli::CommonCmd::Features(_)
@@ -69,5 +76,6 @@ pub fn main(args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<()> {
                 }
             }
         }
+        Command::InContainer(cmd) => commands::in_container::in_container(&cmd),
     }
 }
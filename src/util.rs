@@ -5,6 +5,7 @@ use std::fs::{self, OpenOptions, Permissions};
 use std::io::{self, ErrorKind};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use std::str;
 
@@ -402,3 +403,33 @@ pub fn crun(args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<()> {
 
     Ok(())
 }
+
+/// Get the root path of an existing container, as reported by `crun state`.
+pub fn get_root_path(global: &liboci_cli::GlobalOpts, container_id: &str) -> Result<Utf8PathBuf> {
+    let mut command = Command::new("crun");
+
+    // e.g., Docker's containerd shim uses a non-default state directory
+    if let Some(root) = &global.root {
+        command.arg("--root").arg(root);
+    }
+
+    let output = command
+        .arg("state")
+        .arg(container_id)
+        .stderr(Stdio::null())
+        .output()?;
+
+    ensure!(
+        output.status.success(),
+        "failed to get the state of container {container_id}"
+    );
+
+    #[derive(Deserialize)]
+    struct ContainerState {
+        rootfs: PathBuf,
+    }
+
+    let state: ContainerState = serde_json::from_slice(&output.stdout)?;
+
+    Ok(state.rootfs.try_into()?)
+}
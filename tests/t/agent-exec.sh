@@ -0,0 +1,14 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+trap '__engine stop --time 0 "$TEST_ID"' EXIT
+
+__engine run --rm --detach --name "$TEST_ID" "${TEST_IMAGES[fedora]}"
+
+__engine exec "$TEST_ID" --backend agent --as fedora \
+    '[[ $( whoami ) == fedora ]]'
+
+[[ "$( __engine exec -i "$TEST_ID" --backend agent --as fedora cat <<< hello )" == hello ]]
+
+exit_code=0
+__engine exec "$TEST_ID" --backend agent --as fedora 'exit 42' || exit_code=$?
+(( exit_code == 42 ))
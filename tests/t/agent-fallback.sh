@@ -0,0 +1,29 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+trap '__engine stop --time 0 "$TEST_ID"' EXIT
+
+# keep sshd from ever running in the guest, which still runs its guest agent
+
+cat >"$TEMP_DIR/user-data" <<EOF_
+#cloud-config
+bootcmd:
+  - systemctl mask --now --no-block sshd.service sshd.socket
+  - systemctl stop --no-block sshd-vsock.socket || true
+EOF_
+
+cat >"$TEMP_DIR/meta-data" <<EOF_
+EOF_
+
+__engine run \
+    --rm --detach \
+    --name "$TEST_ID" \
+    "${TEST_IMAGES[fedora]}" \
+    --cloud-init "$TEMP_DIR"
+
+# without --backend, exec falls back to the guest agent, even without a timeout
+
+[[ "$( __engine exec "$TEST_ID" --as fedora whoami )" == fedora ]]
+
+exit_code=0
+__engine exec "$TEST_ID" --as fedora 'exit 42' || exit_code=$?
+(( exit_code == 42 ))
@@ -0,0 +1,39 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+for os in "${!TEST_IMAGES[@]}"; do
+
+    image="${TEST_IMAGES[$os]}"
+    user="${TEST_IMAGES_DEFAULT_USER[$os]}"
+
+    mkdir -p "$TEMP_DIR/$os"
+
+    __engine run \
+        --rm --detach \
+        --name "$TEST_ID-$os" \
+        --security-opt label=disable \
+        "$image" \
+        --channel "test=$TEMP_DIR/$os/test.sock"
+
+    __engine exec "$TEST_ID-$os" --as "$user" 'test -c "$( readlink -f /dev/crun-vm/channels/test )"'
+
+    python3 -c '
+import socket, sys
+s = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+s.settimeout(60)
+s.connect(sys.argv[1])
+data = b""
+while not data.endswith(b"\n"):
+    data += s.recv(1024)
+sys.stdout.write(data.decode())
+' "$TEMP_DIR/$os/test.sock" > "$TEMP_DIR/$os/received" &
+    reader=$!
+
+    sleep 1
+    __engine exec "$TEST_ID-$os" --as "$user" 'echo hello | sudo tee /dev/crun-vm/channels/test'
+
+    wait "$reader"
+    cmp "$TEMP_DIR/$os/received" <<< hello
+
+    __engine stop --time 0 "$TEST_ID-$os"
+
+done
@@ -0,0 +1,24 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+mkdir "$TEMP_DIR/crash-dumps"
+
+__engine run \
+    --detach \
+    --name "$TEST_ID" \
+    --security-opt label=disable \
+    "${TEST_IMAGES[fedora]}" \
+    --crash-dump "$TEMP_DIR/crash-dumps" \
+    --crash-dump-format kdump-zlib
+
+__engine exec "$TEST_ID" 'echo c > /proc/sysrq-trigger' || true
+
+[[ "$( __engine wait "$TEST_ID" )" == 134 ]]
+
+__engine rm "$TEST_ID"
+
+dumps=( "$TEMP_DIR"/crash-dumps/* )
+(( ${#dumps[@]} == 1 ))
+
+[[ -s "${dumps[0]}/vmcore" ]]
+grep -q '<on_crash>preserve</on_crash>' "${dumps[0]}/domain.xml"
+grep -q 'sysrq: Trigger a crash' "${dumps[0]}/console.log"
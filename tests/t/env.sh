@@ -0,0 +1,26 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+for os in "${!TEST_IMAGES[@]}"; do
+
+    image="${TEST_IMAGES[$os]}"
+    user="${TEST_IMAGES_DEFAULT_USER[$os]}"
+
+    __engine run \
+        --rm --detach \
+        --name "$TEST_ID-$os" \
+        --env "GREETING=hello world" \
+        --env 'QUOTED="quoted"' \
+        --env "$( printf 'MULTILINE=a\nb' )" \
+        "$image"
+
+    [[ "$( __engine exec "$TEST_ID-$os" --as "$user" 'echo "$GREETING"' )" == "hello world" ]]
+    [[ "$( __engine exec "$TEST_ID-$os" --as "$user" 'echo "$QUOTED"' )" == '"quoted"' ]]
+
+    # multi-line values can't be passed along, but don't prevent the VM from running
+    [[ -z "$( __engine exec "$TEST_ID-$os" --as "$user" 'echo "${MULTILINE-}"' )" ]]
+
+    __engine exec "$TEST_ID-$os" --as "$user" 'systemctl show-environment | grep ^GREETING='
+
+    __engine stop --time 0 "$TEST_ID-$os"
+
+done
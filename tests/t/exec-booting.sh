@@ -0,0 +1,15 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+trap '__engine stop --time 0 "$TEST_ID"' EXIT
+
+__engine run --rm --detach --name "$TEST_ID" "${TEST_IMAGES[fedora]}"
+
+# while the VM boots, pam_nologin rejects non-root logins, which must be reported
+# as the VM not having booted yet rather than as access being denied
+
+while true; do
+    exit_code=0
+    __engine exec "$TEST_ID" --as fedora --timeout 2 true || exit_code=$?
+    (( exit_code == 0 )) && break
+    (( exit_code == 251 || exit_code == 252 ))
+done
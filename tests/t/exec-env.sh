@@ -0,0 +1,25 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+trap '__engine stop --time 0 "$TEST_ID"' EXIT
+
+__engine run --rm --detach --name "$TEST_ID" --env FOO=container "${TEST_IMAGES[fedora]}"
+
+__engine exec --env "BAR=it's a test" --workdir /tmp "$TEST_ID" --as fedora '
+    set -ex
+    [[ "$BAR" == "it'"'"'s a test" ]]
+    [[ "$PWD" == /tmp ]]
+    '
+
+__engine exec --workdir /tmp "$TEST_ID" --as fedora '[[ "$PWD" == /tmp ]]'
+__engine exec "$TEST_ID" --as fedora '[[ "$PWD" == /home/fedora ]]'
+
+__engine stop --time 0 "$TEST_ID"
+
+# the container's own working directory isn't reproduced in the guest
+__engine run --rm --detach --name "$TEST_ID" --workdir /nonexistent "${TEST_IMAGES[fedora]}"
+
+__engine exec "$TEST_ID" --as fedora '[[ "$PWD" == /home/fedora ]]'
+__engine exec --workdir /tmp "$TEST_ID" --as fedora '[[ "$PWD" == /tmp ]]'
+
+# user IDs can't be mapped to user names for containerdisks
+! __engine exec --user 1000 "$TEST_ID" true
@@ -0,0 +1,17 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+trap '__engine stop --time 0 "$TEST_ID"' EXIT
+
+__engine run --rm --detach --name "$TEST_ID" "${TEST_IMAGES[fedora]}"
+
+# the VM can't have booted within a second
+
+exit_code=0
+__engine exec "$TEST_ID" --as fedora --timeout 1 true || exit_code=$?
+(( exit_code == 251 ))
+
+# failures of the command itself are reported as is
+
+exit_code=0
+__engine exec "$TEST_ID" --as fedora 'exit 7' || exit_code=$?
+(( exit_code == 7 ))
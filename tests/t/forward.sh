@@ -0,0 +1,37 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+trap '__engine stop "$TEST_ID"' EXIT
+
+image="${TEST_IMAGES[fedora]}"
+user="${TEST_IMAGES_DEFAULT_USER[fedora]}"
+
+__engine run \
+    --rm --detach \
+    --name "$TEST_ID" \
+    --publish 127.0.0.1::8000 \
+    --publish 127.0.0.1::8001 \
+    "$image" \
+    --forward 8000:9000
+
+endpoint=$( __engine port "$TEST_ID" 8000 | tee /dev/stderr )
+other_endpoint=$( __engine port "$TEST_ID" 8001 | tee /dev/stderr )
+
+__engine exec "$TEST_ID" --as "$user"
+
+__engine exec "$TEST_ID" --as "$user" python -m http.server 9000 &
+__engine exec "$TEST_ID" --as "$user" python -m http.server 8001 &
+
+__log 'Ensuring curl succeeds for the forwarded port...'
+
+i=0
+max_tries=30
+
+until [[ "$( curl "$endpoint" 2>/dev/null )" == '<!DOCTYPE HTML>'* ]]; do
+    (( ++i < max_tries ))
+    sleep 1
+done
+
+__log 'Ensuring curl fails for the other port...'
+! curl "$other_endpoint" 2>/dev/null
+
+__engine stop "$TEST_ID"
@@ -0,0 +1,36 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+trap '__engine stop --time 0 "$TEST_ID"' EXIT
+
+user="${TEST_IMAGES_DEFAULT_USER[fedora]}"
+
+__engine run \
+    --rm --detach \
+    --name "$TEST_ID" \
+    --publish 127.0.0.1::1234 \
+    "${TEST_IMAGES[fedora]}" \
+    --gdb --gdb-wait
+
+endpoint=$( __engine port "$TEST_ID" | tee /dev/stderr | cut -d' ' -f3 )
+
+# query why the VM is stopped, then let it run, using the GDB remote protocol
+python3 -c '
+import socket, sys, time
+host, port = sys.argv[1].rsplit(":", 1)
+for _ in range(60):
+    try:
+        s = socket.create_connection((host, int(port)))
+        s.settimeout(10)
+        s.sendall(b"$?#3f")
+        reply = s.recv(1024)
+        break
+    except (ConnectionError, socket.timeout):
+        time.sleep(1)
+else:
+    sys.exit("failed to reach gdbstub")
+assert reply.lstrip(b"+").startswith((b"$S", b"$T")), reply
+s.sendall(b"+$c#63")
+time.sleep(1)
+' "$endpoint"
+
+__engine exec "$TEST_ID" --as "$user"
@@ -0,0 +1,25 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+for os in "${!TEST_IMAGES[@]}"; do
+
+    image="${TEST_IMAGES[$os]}"
+    user="${TEST_IMAGES_DEFAULT_USER[$os]}"
+
+    __engine run \
+        --rm --detach \
+        --name "$TEST_ID-$os" \
+        --add-host my-test-host:192.0.2.1 \
+        "$image"
+
+    __test() {
+        __engine exec "$TEST_ID-$os" --as "$user" \
+            "set -x && [[ \$( getent hosts my-test-host ) == '192.0.2.1 '*my-test-host ]]"
+    }
+
+    __test
+    __engine restart "$TEST_ID-$os"
+    __test
+
+    __engine stop --time 0 "$TEST_ID-$os"
+
+done
@@ -0,0 +1,43 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+trap '__engine rm --force "$TEST_ID" "$TEST_ID-custom"' EXIT
+
+user="${TEST_IMAGES_DEFAULT_USER[fedora]}"
+
+# Usage: __mac_address <container>
+__mac_address() {
+    # the VM has a single network interface besides loopback
+    __engine exec "$1" --as "$user" 'cat /sys/class/net/*/address' |
+        grep -v '^00:00:00:00:00:00$'
+}
+
+# by default, the MAC address is derived from the container ID
+
+__engine run --detach --name "$TEST_ID" "${TEST_IMAGES[fedora]}"
+
+mac=$( __mac_address "$TEST_ID" )
+[[ "$mac" == 52:54:* ]]
+
+__engine restart "$TEST_ID"
+[[ "$( __mac_address "$TEST_ID" )" == "$mac" ]]
+
+# the guest's addresses are reported to the container
+
+while ! __engine exec "$TEST_ID" --container cat /crun-vm/guest-addresses |
+    grep --quiet "^[^ ]* $mac [0-9.]*/[0-9]*\$"; do
+    sleep 1
+done
+
+__engine stop --time 0 "$TEST_ID"
+
+# --mac-address overrides it
+
+__engine run \
+    --detach \
+    --name "$TEST_ID-custom" \
+    "${TEST_IMAGES[fedora]}" \
+    --mac-address 52:54:00:12:34:56
+
+[[ "$( __mac_address "$TEST_ID-custom" )" == 52:54:00:12:34:56 ]]
+
+__engine stop --time 0 "$TEST_ID-custom"
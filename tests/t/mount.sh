@@ -13,7 +13,9 @@ for os in "${!TEST_IMAGES[@]}"; do
         --name "$TEST_ID-$os" \
         --volume "$TEMP_DIR/file:$home/file:z" \
         --volume "$TEMP_DIR:$home/dir:z" \
+        --volume "$TEMP_DIR:$home/dir-ro:z,ro" \
         --mount "type=tmpfs,dst=$home/tmp" \
+        --tmpfs "$home/tmp-opts:size=16m,mode=1777,noexec" \
         "$image"
 
     __test() {
@@ -21,8 +23,8 @@ for os in "${!TEST_IMAGES[@]}"; do
 
         __engine exec "$TEST_ID-$os" --as "$user" "
             set -e
-            [[ -b $home/file ]]
-            sudo cmp -n 6 $home/file <<< hello
+            [[ -f $home/file ]]
+            sudo cmp $home/file <<< hello
             "
 
         __engine exec "$TEST_ID-$os" --as "$user" "
@@ -32,9 +34,22 @@ for os in "${!TEST_IMAGES[@]}"; do
             sudo cmp $home/dir/file <<< hello
             "
 
+        __engine exec "$TEST_ID-$os" --as "$user" "
+            set -e
+            mount -l | grep '^virtiofs-1 on $home/dir-ro type virtiofs (ro'
+            sudo cmp $home/dir-ro/file <<< hello
+            ! sudo touch $home/dir-ro/file
+            "
+
         __engine exec "$TEST_ID-$os" --as "$user" "
             mount -l | grep '^tmpfs on $home/tmp type tmpfs'
             "
+
+        __engine exec "$TEST_ID-$os" --as "$user" "
+            set -e
+            mount -l | grep '^tmpfs on $home/tmp-opts type tmpfs' | grep noexec | grep size=16384k
+            [[ \$( stat -c %a $home/tmp-opts ) == 1777 ]]
+            "
     }
 
     __test
@@ -0,0 +1,26 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+if [[ ! -e /dev/vhost-vsock ]]; then
+    # can't exec into VMs without a network interface otherwise
+    __skip
+fi
+
+image="${TEST_IMAGES[fedora]}"
+user="${TEST_IMAGES_DEFAULT_USER[fedora]}"
+
+__test() {
+    __engine exec "$TEST_ID" --as "$user" \
+        "[[ -z \"\$( ip -o link show | grep -v ': lo:' )\" ]]"
+}
+
+# engine's --network none
+
+__engine run --rm --detach --name "$TEST_ID" --network none "$image"
+__test
+__engine stop --time 0 "$TEST_ID"
+
+# crun-vm's --network none
+
+__engine run --rm --detach --name "$TEST_ID" "$image" --network none
+__test
+__engine stop --time 0 "$TEST_ID"
@@ -0,0 +1,35 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+mkdir "$TEMP_DIR/qmp"
+
+__engine run \
+    --rm --detach \
+    --name "$TEST_ID" \
+    --security-opt label=disable \
+    "${TEST_IMAGES[fedora]}" \
+    --qmp-socket "$TEMP_DIR/qmp/qmp.sock"
+
+# the socket must only ever be accessible to us
+while [[ ! -S "$TEMP_DIR/qmp/qmp.sock" ]]; do
+    sleep 1
+done
+[[ "$( stat -c %a "$TEMP_DIR/qmp/qmp.sock" )" == 600 ]]
+
+python3 -c '
+import json, socket, sys
+s = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+s.connect(sys.argv[1])
+f = s.makefile("rw")
+def execute(command):
+    f.write(json.dumps({"execute": command}) + "\n")
+    f.flush()
+    while True:
+        reply = json.loads(f.readline())
+        if "event" not in reply:
+            return reply
+assert "QMP" in json.loads(f.readline())
+assert execute("qmp_capabilities") == {"return": {}}
+assert execute("query-status")["return"]["running"]
+' "$TEMP_DIR/qmp/qmp.sock"
+
+__engine stop --time 0 "$TEST_ID"
@@ -0,0 +1,28 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+for os in fedora coreos; do
+
+    image="${TEST_IMAGES[$os]}"
+    user="${TEST_IMAGES_DEFAULT_USER[$os]}"
+
+    output=$( __engine run --rm --name "$TEST_ID-$os" "$image" \
+        --run-as "$user" --run 'echo hello' )
+
+    [[ "$output" == hello ]]
+
+    exit_code=0
+    __engine run --rm --name "$TEST_ID-$os" "$image" \
+        --run-as "$user" --run 'exit 42' || exit_code=$?
+
+    (( exit_code == 42 ))
+
+done
+
+# --run-timeout gives up if the VM can't be reached in time, which it can't be
+# within a second
+
+exit_code=0
+__engine run --rm --name "$TEST_ID" "${TEST_IMAGES[fedora]}" \
+    --run-timeout 1 --run true || exit_code=$?
+
+(( exit_code == 251 ))
@@ -0,0 +1,21 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+if [[ "$ENGINE" == docker ]]; then
+    # docker doesn't support --sdnotify
+    __skip
+fi
+
+user="${TEST_IMAGES_DEFAULT_USER[fedora]}"
+
+# with --sdnotify=container, podman-run only returns once the container is ready
+__engine run \
+    --rm --detach \
+    --name "$TEST_ID" \
+    --sdnotify=container \
+    "${TEST_IMAGES[fedora]}"
+
+# so the VM should have already booted
+__engine exec "$TEST_ID" --as "$user" --timeout 1 \
+    systemctl is-active crun-vm-ready.service
+
+__engine stop --time 0 "$TEST_ID"
@@ -0,0 +1,41 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+if [[ "$ENGINE" == docker ]]; then
+    # docker only supports secrets in swarm mode
+    __skip
+fi
+
+printf hunter2 | __engine secret create "$TEST_ID" -
+trap '__engine secret rm "$TEST_ID"' EXIT
+
+for os in "${!TEST_IMAGES[@]}"; do
+
+    image="${TEST_IMAGES[$os]}"
+    user="${TEST_IMAGES_DEFAULT_USER[$os]}"
+
+    __engine run \
+        --rm --detach \
+        --name "$TEST_ID-$os" \
+        --secret "$TEST_ID,mode=0400" \
+        --secret "$TEST_ID,type=env,target=PASSWORD" \
+        "$image"
+
+    # the secret is readable, keeps its mode, and can't be modified even by root
+    __engine exec "$TEST_ID-$os" --as "$user" "
+        set -ex
+        [[ \$( sudo cat /run/secrets/$TEST_ID ) == hunter2 ]]
+        [[ \$( sudo stat -c %a /run/secrets/$TEST_ID ) == 400 ]]
+        ! sudo sh -c 'echo oops >> /run/secrets/$TEST_ID'
+        [[ \$( sudo cat /run/secrets/$TEST_ID ) == hunter2 ]]
+        "
+
+    # env secrets are set in the VM but never stored on its disk
+    __engine exec "$TEST_ID-$os" --as "$user" "
+        set -ex
+        [[ \$PASSWORD == hunter2 ]]
+        ! sudo grep -rqs hunter2 /etc /var/lib/cloud
+        "
+
+    __engine stop --time 0 "$TEST_ID-$os"
+
+done
@@ -0,0 +1,42 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+for os in "${!TEST_IMAGES[@]}"; do
+
+    image="${TEST_IMAGES[$os]}"
+    user="${TEST_IMAGES_DEFAULT_USER[$os]}"
+
+    # open a pseudo-terminal pair, and read a line from the primary side
+    python3 -c '
+import os, pty, sys, tty
+primary, secondary = pty.openpty()
+tty.setraw(secondary)
+with open(sys.argv[1], "w") as f:
+    f.write(os.ttyname(secondary))
+data = b""
+while not data.endswith(b"\n"):
+    data += os.read(primary, 1024)
+sys.stdout.write(data.decode())
+' "$TEMP_DIR/pty-$os" > "$TEMP_DIR/received-$os" &
+    reader=$!
+
+    while [[ ! -s "$TEMP_DIR/pty-$os" ]]; do sleep 0.1; done
+
+    __engine run \
+        --rm --detach \
+        --name "$TEST_ID-$os" \
+        --device "$( cat "$TEMP_DIR/pty-$os" ):/dev/ttyharness" \
+        "$image"
+
+    __engine exec "$TEST_ID-$os" --as "$user" '
+        set -e
+        [[ "$( readlink -f /dev/ttyharness )" == /dev/ttyS1 ]]
+        sudo stty -F /dev/ttyharness raw
+        echo hello | sudo tee /dev/ttyharness
+        '
+
+    wait "$reader"
+    cmp "$TEMP_DIR/received-$os" <<< hello
+
+    __engine stop --time 0 "$TEST_ID-$os"
+
+done
@@ -0,0 +1,29 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+for os in "${!TEST_IMAGES[@]}"; do
+
+    image="${TEST_IMAGES[$os]}"
+    user="${TEST_IMAGES_DEFAULT_USER[$os]}"
+    home="${TEST_IMAGES_DEFAULT_USER_HOME[$os]}"
+
+    echo hello > "$TEMP_DIR/file"
+
+    __engine run \
+        --rm --detach \
+        --name "$TEST_ID-$os" \
+        --volume "$TEMP_DIR:$home/dir:z" \
+        --volume "$TEMP_DIR:$home/dir-ro:z,ro" \
+        "$image" \
+        --share-backend 9p
+
+    __engine exec "$TEST_ID-$os" --as "$user" "
+        set -e
+        mount -l | grep '^virtiofs-0 on $home/dir type 9p'
+        sudo cmp $home/dir/file <<< hello
+        mount -l | grep '^virtiofs-1 on $home/dir-ro type 9p (ro'
+        ! sudo touch $home/dir-ro/file
+        "
+
+    __engine stop --time 0 "$TEST_ID-$os"
+
+done
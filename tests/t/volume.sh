@@ -0,0 +1,62 @@
+# SPDX-License-Identifier: GPL-2.0-or-later
+
+for os in "${!TEST_IMAGES[@]}"; do
+
+    image="${TEST_IMAGES[$os]}"
+    user="${TEST_IMAGES_DEFAULT_USER[$os]}"
+    home="${TEST_IMAGES_DEFAULT_USER_HOME[$os]}"
+
+    echo hello > "$TEMP_DIR/file"
+
+    __engine volume create "$TEST_ID-$os"
+
+    overlay=()
+    if [[ "$ENGINE" != docker ]]; then
+        overlay=( --volume "$TEMP_DIR:$home/overlay:O" )
+    fi
+
+    __engine run \
+        --rm --detach \
+        --name "$TEST_ID-$os" \
+        --volume "$TEST_ID-$os:$home/volume" \
+        "${overlay[@]}" \
+        "$image"
+
+    __engine exec "$TEST_ID-$os" --as "$user" "
+        set -e
+        mount -l | grep '^virtiofs-0 on $home/volume type virtiofs'
+        echo world | sudo tee $home/volume/file
+        "
+
+    if [[ "$ENGINE" != docker ]]; then
+        __engine exec "$TEST_ID-$os" --as "$user" "
+            set -e
+            mount -l | grep '^virtiofs-1 on $home/overlay type virtiofs'
+            sudo cmp $home/overlay/file <<< hello
+            echo world | sudo tee $home/overlay/file
+            "
+
+        # changes to overlay volumes don't reach the host
+        cmp "$TEMP_DIR/file" <<< hello
+    fi
+
+    __engine stop --time 0 "$TEST_ID-$os"
+
+    # named volumes persist across containers
+    __engine run \
+        --rm --detach \
+        --name "$TEST_ID-$os" \
+        --volume "$TEST_ID-$os:$home/volume:ro" \
+        "$image"
+
+    __engine exec "$TEST_ID-$os" --as "$user" "
+        set -e
+        sudo cmp $home/volume/file <<< world
+        ! sudo touch $home/volume/file
+        "
+
+    __engine stop --time 0 "$TEST_ID-$os"
+
+    __engine volume rm "$TEST_ID-$os"
+
+done